@@ -5,9 +5,15 @@ use serde::{
 };
 use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
 use std::io::{Read,Write};
+use std::borrow::Borrow;
+use std::ops::{Bound,Range,RangeBounds};
+use std::cmp::Ordering;
+use std::hash::{Hash,Hasher,BuildHasher};
+use std::collections::{BTreeMap,HashMap};
+use std::sync::Arc;
 use crate::{
-    Flags,Filled,Binary,
-    civs::{Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT},
+    Flags,Filled,Binary,Comparator,NaturalOrder,
+    civs::{Slot,MergedRuns,RunPos,CivConfig,CivConfigError,CompactionPolicy,LevelInfo,BinaryCounter,merge_sorted,in_range,range_span},
 };
 
 pub enum RemovedItem<'t,V> {
@@ -25,6 +31,15 @@ impl<'t,V> RemovedItem<'t,V> {
         }
     }
 }
+impl<'t,V: Default> RemovedItem<'t,V> {
+    pub fn take(self) -> V {
+        // tombstoned value is replaced by the default one and dropped by the caller
+        match self {
+            RemovedItem::Ref(r) => std::mem::take(r),
+            RemovedItem::Owned(v) => v,
+        }
+    }
+}
 impl<'t,V> AsRef<V> for RemovedItem<'t,V> {
     fn as_ref(&self) -> &V {
         match self {
@@ -80,7 +95,7 @@ impl<K,V> std::convert::TryFrom<SerdeMapMultiSlot<K,V>> for MapMultiSlot<K,V> {
         }
         Ok(MapMultiSlot {
             capacity: slot.capacity,
-            flags: Flags(slot.flags),
+            flags: Flags::from_bits(slot.flags),
             keys: slot.keys,
             values: slot.values,
         })
@@ -117,7 +132,7 @@ impl<K,V> MapMultiSlot<K,V> {
         self.flags.heap_mem() + self.keys.capacity() * std::mem::size_of::<K>() + self.values.capacity() * std::mem::size_of::<V>()
     }
 }
-impl<K: Ord, V> MapMultiSlot<K,V> {
+impl<K, V> MapMultiSlot<K,V> {
     pub(crate) fn new(data: Vec<(K,V)>) -> MapMultiSlot<K,V> {
         let len = data.len();
         let mut keys = Vec::with_capacity(len);
@@ -133,8 +148,7 @@ impl<K: Ord, V> MapMultiSlot<K,V> {
             values: values,
         }
     }
-    fn new_empty(sz: usize, slot_sz: usize) -> MapMultiSlot<K,V> {
-        let cap = slot_sz * (0x1 << (sz-1));
+    fn new_empty(cap: usize) -> MapMultiSlot<K,V> {
         MapMultiSlot {
             capacity: cap,
             flags: Flags::nulls(cap),
@@ -142,15 +156,24 @@ impl<K: Ord, V> MapMultiSlot<K,V> {
             values: Vec::with_capacity(cap),
         }
     }
+    fn new_unallocated(cap: usize) -> MapMultiSlot<K,V> {
+        // empty level, memory is reserved on the first merge into it
+        MapMultiSlot {
+            capacity: cap,
+            flags: Flags::tmp(),
+            keys: Vec::new(),
+            values: Vec::new(),
+        }
+    }
     fn empty(&self) -> bool {
         self.keys.len() == 0
     }
     fn check_len(&self) -> usize {
         self.flags.0.iter().fold(0,|acc,x| acc + x.count_ones() as usize)
     }
-    fn contains(&self, k: &K) -> Option<usize> {
-        if (self.keys.len() == 0)||(*k < self.keys[0])||(*k > self.keys[self.keys.len()-1]) { return None; }
-        match self.keys.binary_search(k) {
+    fn contains<Q: ?Sized, C: Comparator<Q>>(&self, k: &Q, cmp: &C) -> Option<usize> where K: Borrow<Q> {
+        if (self.keys.len() == 0)||(cmp.compare(k,self.keys[0].borrow()) == Ordering::Less)||(cmp.compare(k,self.keys[self.keys.len()-1].borrow()) == Ordering::Greater) { return None; }
+        match self.keys.binary_search_by(|x| cmp.compare(x.borrow(),k)) {
             Ok(idx) => match self.flags.get(idx) {
                 true => Some(idx),
                 false => None,
@@ -163,6 +186,16 @@ impl<K: Ord, V> MapMultiSlot<K,V> {
         self.keys.clear();
         self.values.clear();
     }
+    fn split_off(&mut self, at: usize) -> MapMultiSlot<K,V> {
+        // the right part keeps the capacity of the level
+        let len = self.keys.len();
+        MapMultiSlot {
+            capacity: self.capacity,
+            flags: self.flags.split_off(at,len),
+            keys: self.keys.split_off(at),
+            values: self.values.split_off(at),
+        }
+    }
     fn shrink_to_fit(&mut self) {
         self.keys.shrink_to_fit();
         self.values.shrink_to_fit();
@@ -252,6 +285,51 @@ impl<'t,K,V> Iterator for MapMultiSlotDrainIterator<'t,K,V> {
 }
 
 
+pub struct ExtractIf<'t,K,V,C: Comparator<K>,F> {
+    map: &'t mut CivMap<K,V,C>,
+    pred: F,
+    slot_idx: usize,
+    msi: usize,
+    idx: usize,
+}
+impl<'t,K: Clone,V: Default,C: Comparator<K>,F: FnMut(&K,&mut V) -> bool> Iterator for ExtractIf<'t,K,V,C,F> {
+    type Item = (K,V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = &mut *self.map;
+        while self.slot_idx < map.slot.data.len() {
+            let (k,v) = &mut map.slot.data[self.slot_idx];
+            if (self.pred)(k,v) {
+                map.len -= 1;
+                return Some(map.slot.data.swap_remove(self.slot_idx));
+            }
+            self.slot_idx += 1;
+        }
+        while self.msi < map.data.len() {
+            let ms = &mut map.data[self.msi];
+            while self.idx < ms.keys.len() {
+                let idx = self.idx;
+                self.idx += 1;
+                if ms.flags.get(idx) && (self.pred)(&ms.keys[idx],&mut ms.values[idx]) {
+                    ms.flags.unset(idx);
+                    map.len -= 1;
+                    map.tombs += 1;
+                    return Some((ms.keys[idx].clone(),std::mem::take(&mut ms.values[idx])));
+                }
+            }
+            self.msi += 1;
+            self.idx = 0;
+        }
+        None
+    }
+}
+impl<'t,K,V,C: Comparator<K>,F> Drop for ExtractIf<'t,K,V,C,F> {
+    fn drop(&mut self) {
+        // the tombstones left by the iterator are reclaimed at once
+        self.map.check_levels();
+    }
+}
+
 pub struct Iter<'t,K,V> {
     slot_iter: Option<std::slice::Iter<'t,(K,V)>>,
     cur_data_iter: Option<MapMultiSlotFilterIterator<'t,K,V>>,
@@ -277,46 +355,205 @@ impl<'t,K,V> Iterator for Iter<'t,K,V> {
     }
 }
 
+pub struct SortedIter<'t,K,V,C = NaturalOrder> {
+    map: &'t CivMap<K,V,C>,
+    runs: MergedRuns<'t,K,V,C>,
+}
+impl<'t,K,V,C: Comparator<K>> Iterator for SortedIter<'t,K,V,C> {
+    type Item = (&'t K, &'t V);
 
-const CURRENT_CIVS_MAP_VERSION: (u32,u32) = (0,1);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runs.next().map(|(k,pos)| (k,self.map.value_at(pos)))
+    }
+}
+impl<'t,K,V,C: Comparator<K>> DoubleEndedIterator for SortedIter<'t,K,V,C> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.runs.next_back().map(|(k,pos)| (k,self.map.value_at(pos)))
+    }
+}
+pub struct Cursor<'t,K,V,C = NaturalOrder> {
+    map: &'t CivMap<K,V,C>,
+    runs: MergedRuns<'t,K,V,C>,
+}
+impl<'t,K,V,C: Comparator<K>> Cursor<'t,K,V,C> {
+    pub fn seek<Q: ?Sized>(&mut self, k: &Q) where K: Borrow<Q>, C: Comparator<Q> {
+        // before the first key >= k
+        self.runs.seek(k);
+    }
+    pub fn seek_first(&mut self) {
+        self.runs.seek_first();
+    }
+    pub fn seek_last(&mut self) {
+        self.runs.seek_last();
+    }
+    pub fn peek_next(&mut self) -> Option<(&'t K, &'t V)> {
+        self.runs.peek_next().map(|(k,pos)| (k,self.map.value_at(pos)))
+    }
+    pub fn peek_prev(&self) -> Option<(&'t K, &'t V)> {
+        self.runs.peek_prev().map(|(run,idx,k)| (k,self.map.value_at(self.runs.pos(run,idx))))
+    }
+    pub fn prev(&mut self) -> Option<(&'t K, &'t V)> {
+        self.runs.prev().map(|(k,pos)| (k,self.map.value_at(pos)))
+    }
+}
+impl<'t,K,V,C: Comparator<K>> Iterator for Cursor<'t,K,V,C> {
+    type Item = (&'t K, &'t V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runs.next().map(|(k,pos)| (k,self.map.value_at(pos)))
+    }
+}
+pub enum Entry<'t,K,V,C = NaturalOrder> {
+    Occupied(OccupiedEntry<'t,K,V,C>),
+    Vacant(VacantEntry<'t,K,V,C>),
+}
+impl<'t,K,V,C: Comparator<K>> Entry<'t,K,V,C> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+    pub fn or_insert(self, v: V) -> &'t mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(v),
+        }
+    }
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'t mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(f()),
+        }
+    }
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Entry<'t,K,V,C> {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(e.get_mut());
+                Entry::Occupied(e)
+            },
+            e @ Entry::Vacant(_) => e,
+        }
+    }
+}
+impl<'t,K,V: Default,C: Comparator<K>> Entry<'t,K,V,C> {
+    pub fn or_default(self) -> &'t mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+pub struct OccupiedEntry<'t,K,V,C = NaturalOrder> {
+    map: &'t mut CivMap<K,V,C>,
+    pos: RunPos,
+}
+impl<'t,K,V,C: Comparator<K>> OccupiedEntry<'t,K,V,C> {
+    pub fn key(&self) -> &K {
+        match self.pos {
+            RunPos::Slot(idx) => &self.map.slot.data[idx].0,
+            RunPos::Level(msi,idx) => &self.map.data[msi].keys[idx],
+        }
+    }
+    pub fn get(&self) -> &V {
+        self.map.value_at(self.pos)
+    }
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.value_at_mut(self.pos)
+    }
+    pub fn into_mut(self) -> &'t mut V {
+        self.map.value_at_mut(self.pos)
+    }
+    pub fn insert(&mut self, v: V) -> V {
+        std::mem::replace(self.get_mut(),v)
+    }
+    pub fn remove(self) -> RemovedItem<'t,V> {
+        self.map.len -= 1;
+        match self.pos {
+            RunPos::Slot(idx) => RemovedItem::Owned(self.map.slot.data.swap_remove(idx).1),
+            RunPos::Level(msi,idx) => {
+                self.map.tombs += 1;
+                self.map.data[msi].flags.unset(idx);
+                if self.map.overfull(msi) {
+                    if let Some((_,v)) = self.map.purge_level(msi,Some(idx)) { return RemovedItem::Owned(v); }
+                }
+                RemovedItem::Ref(&mut self.map.data[msi].values[idx])
+            },
+        }
+    }
+}
+
+pub struct VacantEntry<'t,K,V,C = NaturalOrder> {
+    map: &'t mut CivMap<K,V,C>,
+    key: K,
+}
+impl<'t,K,V,C: Comparator<K>> VacantEntry<'t,K,V,C> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+    pub fn into_key(self) -> K {
+        self.key
+    }
+    pub fn insert(self, v: V) -> &'t mut V {
+        self.map.push_new(self.key,v)
+    }
+}
+
+const CURRENT_CIVS_MAP_VERSION: (u32,u32) = (0,2);
+
+#[derive(Debug)]
+pub enum CivMapBuildError {
+    Unsorted(usize),
+    Duplicate(usize),
+}
 
 #[derive(Debug)]
 pub enum CivMapIoError {
     WriteHeader,
+    WriteConfig(bincode::Error),
     WriteSlot(bincode::Error),
     WriteData(bincode::Error),
     ReadHeader,
+    ReadConfig(bincode::Error),
     ReadSlot(bincode::Error),
     ReadData(bincode::Error),
     InvalidHeader,
     InvalidVersion(u32,u32),
+    InvalidConfig(CivConfigError),
 }
 
-impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Binary for CivMap<K,V> {
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned, C: Comparator<K> + Default> Binary for CivMap<K,V,C> {
     type IoError = CivMapIoError;
     fn memory(&self) -> usize {
         let mut data_mem = self.data.capacity() * std::mem::size_of::<MapMultiSlot<K,V>>();
         for ms in &self.data {
             data_mem += ms.heap_mem();
         }
-        std::mem::size_of::<CivMap<K,V>>() + self.slot.heap_mem() + data_mem
+        std::mem::size_of::<CivMap<K,V,C>>() + self.slot.heap_mem() + data_mem
     }
     fn into_writer<W: Write>(&self, mut wrt: W) -> Result<(),Self::IoError> {
         let version = CURRENT_CIVS_MAP_VERSION;
         write!(wrt,"CIVM").map_err(|_|CivMapIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivMapIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivMapIoError::WriteHeader)?;
+        bincode::serialize_into(&mut wrt,&self.config).map_err(CivMapIoError::WriteConfig)?;
         bincode::serialize_into(&mut wrt,&self.slot).map_err(CivMapIoError::WriteSlot)?;
         bincode::serialize_into(&mut wrt,&self.data).map_err(CivMapIoError::WriteData)
     }
-    fn from_reader<R: Read>(mut rdr: R) -> Result<CivMap<K,V>,Self::IoError> {
+    fn from_reader<R: Read>(mut rdr: R) -> Result<CivMap<K,V,C>,Self::IoError> {
         let mut buf = [0; 4];
         rdr.read_exact(&mut buf).map_err(|_|CivMapIoError::ReadHeader)?;
         if buf != "CIVM".as_bytes()[0..4] { return Err(CivMapIoError::InvalidHeader); }
         let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
         let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivMapIoError::ReadHeader)?;
-        if (maj != 0)||(min != 1) { return Err(CivMapIoError::InvalidVersion(maj,min)); }
+        if (maj != 0)||((min != 1)&&(min != 2)) { return Err(CivMapIoError::InvalidVersion(maj,min)); }
+        // version 0.1 has no config, only the slot size is known
+        let config: Option<CivConfig> = match min {
+            1 => None,
+            _ => Some(bincode::deserialize_from(&mut rdr).map_err(CivMapIoError::ReadConfig)?),
+        };
         let slot: Slot<K,V> = bincode::deserialize_from(&mut rdr).map_err(CivMapIoError::ReadSlot)?;
+        let config = config.unwrap_or_else(|| CivConfig::with_slot_size(slot.max_size()));
+        let config = config.validate().map_err(CivMapIoError::InvalidConfig)?;
+        if slot.max_size() != config.slot_size() { return Err(CivMapIoError::InvalidConfig(CivConfigError::SlotSize(slot.max_size()))); }
         let data: Vec<MapMultiSlot<K,V>> = bincode::deserialize_from(&mut rdr).map_err(CivMapIoError::ReadData)?;
         let mut len = slot.len();
         let mut tombs = 0;
@@ -330,6 +567,10 @@ impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Bin
             tombs: tombs,
             slot: slot,
             data: data,
+            cmp: C::default(),
+            config,
+            // like the comparator, the policy is not stored
+            policy: Arc::new(BinaryCounter),
             
             tmp_merge_keys: Vec::new(),
             tmp_merge_values: Vec::new(),
@@ -340,16 +581,19 @@ impl<K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Bin
 
 
 #[derive(Clone)]
-pub struct CivMap<K,V> {
+pub struct CivMap<K,V,C = NaturalOrder> {
     len: usize,
     tombs: usize,
     slot: Slot<K,V>,
     data: Vec<MapMultiSlot<K,V>>,
+    cmp: C,
+    config: CivConfig,
+    policy: Arc<dyn CompactionPolicy>,
 
     tmp_merge_keys: Vec<K>,
     tmp_merge_values: Vec<V>,
 }
-impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for CivMap<K,V> {
+impl<K: std::fmt::Debug, V: std::fmt::Debug, C> std::fmt::Debug for CivMap<K,V,C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CivMap")
             .field("len", &self.len)
@@ -361,11 +605,63 @@ impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for CivMap<K,V> {
 }     
 impl<K: Ord, V> CivMap<K,V> {
     pub fn new() -> CivMap<K,V> {
+        CivMap::with_comparator(NaturalOrder)
+    }
+    pub fn with_capacity(n: usize) -> CivMap<K,V> {
+        let mut map = CivMap::new();
+        map.reserve(n);
+        map
+    }
+    pub fn with_config(config: CivConfig) -> CivMap<K,V> {
+        CivMap::with_config_and_comparator(config,NaturalOrder)
+    }
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K,V)>>(iter: I) -> Result<CivMap<K,V>,CivMapBuildError> {
+        let iter = iter.into_iter();
+        let mut keys: Vec<K> = Vec::with_capacity(iter.size_hint().0);
+        let mut values = Vec::with_capacity(iter.size_hint().0);
+        for (i,(k,v)) in iter.enumerate() {
+            if let Some(last) = keys.last() {
+                match last.cmp(&k) {
+                    Ordering::Less => {},
+                    Ordering::Equal => return Err(CivMapBuildError::Duplicate(i)),
+                    Ordering::Greater => return Err(CivMapBuildError::Unsorted(i)),
+                }
+            }
+            keys.push(k);
+            values.push(v);
+        }
+        let mut map = CivMap::new();
+        map.load_sorted(keys,values);
+        Ok(map)
+    }
+    pub fn from_sorted_iter_unchecked<I: IntoIterator<Item = (K,V)>>(iter: I) -> CivMap<K,V> {
+        // iter must be strictly increasing by key; unsorted or repeated keys are a logic error, not UB:
+        //   lookups may miss entries and iteration order is unspecified, debug builds panic on them
+        let (keys,values): (Vec<K>,Vec<V>) = iter.into_iter().unzip();
+        debug_assert!(keys.windows(2).all(|w| w[0] < w[1]),"from_sorted_iter_unchecked: keys are not strictly increasing");
+        let mut map = CivMap::new();
+        map.load_sorted(keys,values);
+        map
+    }
+    pub fn from_unsorted_vec(data: Vec<(K,V)>) -> CivMap<K,V> {
+        let mut map = CivMap::new();
+        map.load_unsorted(data);
+        map
+    }
+}
+impl<K, V, C: Comparator<K>> CivMap<K,V,C> {
+    pub fn with_comparator(cmp: C) -> CivMap<K,V,C> {
+        CivMap::with_config_and_comparator(CivConfig::default(),cmp)
+    }
+    pub fn with_config_and_comparator(config: CivConfig, cmp: C) -> CivMap<K,V,C> {
         CivMap {
             len: 0,
             tombs: 0,
-            slot: Slot::new(),
+            slot: Slot::new(config.slot_size()),
             data: Vec::new(),
+            cmp,
+            config,
+            policy: Arc::new(BinaryCounter),
 
             tmp_merge_keys: Vec::new(),
             tmp_merge_values: Vec::new(),
@@ -388,6 +684,258 @@ impl<K: Ord, V> CivMap<K,V> {
         }
     }
 
+    fn load_unsorted(&mut self, data: Vec<(K,V)>) {
+        let data = self.sort_unique(data);
+        let (keys,values) = data.into_iter().unzip();
+        self.load_sorted(keys,values);
+    }
+    fn sort_unique(&self, mut data: Vec<(K,V)>) -> Vec<(K,V)> {
+        // the last value of a duplicated key wins, as with insert
+        let cmp = &self.cmp;
+        data.sort_by(|(k1,_),(k2,_)|cmp.compare(k1,k2));
+        data.dedup_by(|next,prev| match cmp.compare(&next.0,&prev.0) {
+            Ordering::Equal => {
+                std::mem::swap(&mut next.1,&mut prev.1);
+                true
+            },
+            _ => false,
+        });
+        data
+    }
+    fn take_all(&mut self) -> Vec<(K,V)> {
+        // unsorted live entries, the map is cleared
+        let mut data = Vec::with_capacity(self.len);
+        data.append(&mut self.slot.data);
+        for ms in &mut self.data {
+            data.extend(ms.filtered_drain());
+        }
+        self.clear();
+        data
+    }
+    fn take_sorted(&mut self) -> Vec<(K,V)> {
+        // sorted live entries, the map is cleared;
+        //   levels grow geometrically, so merging them from the smallest up is linear
+        let cmp = &self.cmp;
+        let mut data: Vec<(K,V)> = self.slot.sorted_drain(cmp).collect();
+        for ms in &mut self.data {
+            let run = ms.filtered_drain().collect();
+            data = merge_sorted(data,run,|a,b|cmp.compare(&a.0,&b.0),|a,_|a);
+        }
+        self.clear();
+        data
+    }
+    pub fn absorb_with<F: FnMut(&K,V,V) -> V>(&mut self, mut other: CivMap<K,V,C>, f: F) {
+        // f(key, old, new) resolves keys present in both maps
+        let run = other.take_sorted();
+        self.merge_run(run,f);
+    }
+    fn replace_run(&mut self, mut run: Vec<(K,V)>) {
+        // values of the sorted run win as with insert; the values of keys already in the levels
+        //   are replaced in place, so the levels above stay as they are
+        run.retain_mut(|(k,v)| match self.multy_contains(k) {
+            Some((msi,idx)) => {
+                std::mem::swap(&mut self.data[msi].values[idx],v);
+                false
+            },
+            None => true,
+        });
+        self.merge_run(run,|_,_,new| new);
+    }
+    fn merge_run<F: FnMut(&K,V,V) -> V>(&mut self, run: Vec<(K,V)>, mut f: F) {
+        // a sorted run of distinct keys goes with the slot and the lowest levels into the first level
+        //   that fits them all, the levels above it are not rewritten;
+        //   a key present in a level above needs f, so the merge reaches up to that level
+        if run.is_empty() { return; }
+        let top = run.iter().filter_map(|(k,_)| self.multy_contains(k).map(|(msi,_)| msi)).max().unwrap_or(0);
+        let mut cnt = self.slot.len() + run.len();
+        let mut t = 0;
+        loop {
+            if t == self.data.len() {
+                self.data.push(MapMultiSlot::new_unallocated(self.level_capacity(t)));
+            }
+            let ms = &self.data[t];
+            cnt += ms.flags.rank(ms.keys.len());
+            if (ms.capacity >= cnt) && (t >= top) { break; }
+            t += 1;
+        }
+        let cmp = &self.cmp;
+        let mut data: Vec<(K,V)> = self.slot.sorted_drain(cmp).collect();
+        self.slot.clear();
+        for ms in &mut self.data[..= t] {
+            let level = ms.filtered_drain().collect();
+            data = merge_sorted(data,level,|a,b|cmp.compare(&a.0,&b.0),|a,_|a);
+            ms.clear();
+        }
+        let data = merge_sorted(data,run,|a,b|cmp.compare(&a.0,&b.0),|(k,old),(_,new)| {
+            let v = f(&k,old,new);
+            (k,v)
+        });
+        let ms = &mut self.data[t];
+        ms.reserve(data.len());
+        for (k,v) in data {
+            ms.keys.push(k);
+            ms.values.push(v);
+        }
+        let c = ms.keys.len();
+        ms.flags.set_ones(c);
+        self.recount();
+    }
+    pub fn split_off<Q: ?Sized>(&mut self, k: &Q) -> CivMap<K,V,C> where K: Borrow<Q>, C: Comparator<Q> + Clone {
+        // keys >= k are moved out, every level is split in place
+        //   and the right part keeps the level layout
+        let mut right = CivMap::with_config_and_comparator(self.config,self.cmp.clone());
+        right.policy = self.policy.clone();
+        let cmp = &self.cmp;
+        let mut i = 0;
+        while i < self.slot.data.len() {
+            match cmp.compare(self.slot.data[i].0.borrow(),k) {
+                Ordering::Less => i += 1,
+                _ => right.slot.data.push(self.slot.data.swap_remove(i)),
+            }
+        }
+        for ms in &mut self.data {
+            let at = ms.keys.partition_point(|x| cmp.compare(x.borrow(),k) == Ordering::Less);
+            right.data.push(ms.split_off(at));
+        }
+        while right.data.last().is_some_and(|ms| ms.empty()) {
+            right.data.pop();
+        }
+        self.recount();
+        right.recount();
+        // the split levels keep their capacity
+        for map in [&mut *self,&mut right] {
+            map.check_vacant();
+            map.check_levels();
+        }
+        right
+    }
+    pub fn append(&mut self, other: &mut CivMap<K,V,C>) {
+        // other is emptied and its values win as with insert; its entries go with the lowest levels
+        //   into the first level that fits them, for ordered maps this is a concatenation
+        //   and the levels above are not rewritten
+        if other.len == 0 { return; }
+        if (self.len == 0) && (self.config == other.config) {
+            std::mem::swap(self,other);
+            std::mem::swap(&mut self.policy,&mut other.policy);
+            return;
+        }
+        let run = other.take_sorted();
+        self.replace_run(run);
+    }
+    pub fn reserve(&mut self, additional: usize) {
+        // every level which gets data before len + additional is reached is allocated up front;
+        //   tmp_merge_* only trade places with the level being merged into and need no memory of their own
+        let n = self.len.checked_add(additional).expect("capacity overflow");
+        let mut lvl = 0;
+        while self.level_capacity(lvl) <= n {
+            if self.data.len() == lvl {
+                self.data.push(MapMultiSlot::new_unallocated(self.level_capacity(lvl)));
+            }
+            let ms = &mut self.data[lvl];
+            ms.reserve(ms.capacity - ms.keys.len());
+            lvl += 1;
+        }
+    }
+    pub fn config(&self) -> &CivConfig {
+        &self.config
+    }
+    pub fn set_policy<P: CompactionPolicy + 'static>(&mut self, policy: P) {
+        // any level layout is valid for any policy, so it may be changed at any time
+        self.policy = Arc::new(policy);
+    }
+    fn level_capacity(&self, n: usize) -> usize {
+        self.config.level_capacity(self.slot.max_size(),n)
+    }
+    fn recount(&mut self) {
+        // len and tombs from scratch, after levels were changed in bulk
+        self.len = self.slot.len();
+        self.tombs = 0;
+        for ms in &self.data {
+            if ms.empty() { continue; }
+            let live = ms.flags.rank(ms.keys.len());
+            self.len += live;
+            self.tombs += ms.capacity - live;
+        }
+    }
+    fn load_sorted(&mut self, keys: Vec<K>, values: Vec<V>) {
+        // everything goes into the single level that fits the data
+        self.clear();
+        let sz = self.slot.max_size();
+        let n = keys.len();
+        self.len = n;
+        if n < sz {
+            self.slot.data.extend(keys.into_iter().zip(values));
+            return;
+        }
+        let mut lvl = 0;
+        while self.level_capacity(lvl) < n { lvl += 1; }
+        for i in 0 .. lvl {
+            self.data.push(MapMultiSlot::new_unallocated(self.level_capacity(i)));
+        }
+        self.data.push(MapMultiSlot {
+            capacity: self.level_capacity(lvl),
+            flags: Flags::ones(n),
+            keys,
+            values,
+        });
+        self.tombs = self.level_capacity(lvl) - n;
+    }
+
+    pub fn iter_sorted(&self) -> SortedIter<'_,K,V,C> {
+        SortedIter {
+            map: self,
+            runs: self.runs(),
+        }
+    }
+    pub fn range<Q: ?Sized, R: RangeBounds<Q>>(&self, range: R) -> SortedIter<'_,K,V,C> where K: Borrow<Q>, C: Comparator<Q> {
+        let mut runs = self.runs();
+        runs.restrict(&range);
+        SortedIter {
+            map: self,
+            runs,
+        }
+    }
+    pub fn cursor(&self) -> Cursor<'_,K,V,C> {
+        Cursor {
+            map: self,
+            runs: self.runs(),
+        }
+    }
+    pub fn floor<Q: ?Sized>(&self, k: &Q) -> Option<(&K,&V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.range((Bound::Unbounded,Bound::Included(k))).next_back()
+    }
+    pub fn ceiling<Q: ?Sized>(&self, k: &Q) -> Option<(&K,&V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.range((Bound::Included(k),Bound::Unbounded)).next()
+    }
+    pub fn first(&self) -> Option<(&K,&V)> {
+        self.iter_sorted().next()
+    }
+    pub fn last(&self) -> Option<(&K,&V)> {
+        self.iter_sorted().next_back()
+    }
+    pub fn rank<Q: ?Sized>(&self, k: &Q) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        // number of keys < k
+        self.runs().rank(k)
+    }
+    pub fn select(&self, n: usize) -> Option<(&K,&V)> {
+        self.runs().select(n).map(|(k,pos)| (k,self.value_at(pos)))
+    }
+    fn runs(&self) -> MergedRuns<'_,K,V,C> {
+        MergedRuns::new(&self.slot,self.data.iter().map(|ms|(&ms.keys[..],&ms.flags)).collect(),&self.cmp)
+    }
+    fn value_at(&self, pos: RunPos) -> &V {
+        match pos {
+            RunPos::Slot(idx) => &self.slot.data[idx].1,
+            RunPos::Level(msi,idx) => &self.data[msi].values[idx],
+        }
+    }
+    fn value_at_mut(&mut self, pos: RunPos) -> &mut V {
+        match pos {
+            RunPos::Slot(idx) => &mut self.slot.data[idx].1,
+            RunPos::Level(msi,idx) => &mut self.data[msi].values[idx],
+        }
+    }
+
     pub fn clear(&mut self) {
         self.len = 0;
         self.tombs = 0;
@@ -397,22 +945,22 @@ impl<K: Ord, V> CivMap<K,V> {
         self.tmp_merge_values.clear();
     }
     
-    pub fn contains(&self, k: &K) -> bool {
-        match self.slot.contains(k) {
+    pub fn contains<Q: ?Sized>(&self, k: &Q) -> bool where K: Borrow<Q>, C: Comparator<Q> {
+        match self.slot.contains(k,&self.cmp) {
             Some(_) => true,
             None => self.multy_contains(k).is_some(),
         }
     }    
-    fn multy_contains(&self, k: &K) -> Option<(usize,usize)> {
+    fn multy_contains<Q: ?Sized>(&self, k: &Q) -> Option<(usize,usize)> where K: Borrow<Q>, C: Comparator<Q> {
         for (n,ms) in self.data.iter().enumerate() {
-            if let Some(idx) = ms.contains(k) {
+            if let Some(idx) = ms.contains(k,&self.cmp) {
                 return Some((n,idx));
             }
         }
         None
     }
-    pub fn get(&self, k: &K) -> Option<&V> {
-        match self.slot.get(k) {
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V> where K: Borrow<Q>, C: Comparator<Q> {
+        match self.slot.get(k,&self.cmp) {
             r @ Some(_) => r,
             None => match self.multy_contains(k) {
                 Some((msi,idx)) => Some(&self.data[msi].values[idx]),
@@ -420,41 +968,101 @@ impl<K: Ord, V> CivMap<K,V> {
             }
         }
     }
-    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V> where K: Borrow<Q>, C: Comparator<Q> {
         match self.multy_contains(k) {
             Some((msi,idx)) => Some(&mut self.data[msi].values[idx]),
-            None => self.slot.get_mut(k),
+            None => self.slot.get_mut(k,&self.cmp),
         }
     }
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        if let Some((msi,idx)) = self.multy_contains(&k) {
-            let mut tmp = v;
-            std::mem::swap(&mut tmp, &mut self.data[msi].values[idx]);
-            return Some(tmp);
-        }
-        let (r,filled) = self.slot.insert(k,v);
-        if let Filled::Full = filled {
-            if self.data.len() == 0 {
-                self.data.push(self.slot.into_map_multislot());
-            } else {
-                let mut n = 0;
-                while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
-                if n == self.data.len() {
-                    self.data.push(MapMultiSlot::new_empty(n+1,self.slot.max_size()));
-                }
-                if let Err(s) = self.merge_into(n) {
-                    panic!("Unreachable merge_into: {}",s);
-                }
-                if let Err(s) = self.check_tombs(n) {
-                    panic!("Unreachable check_tombs: {}",s);
-                }
-                self.shrink_long();
+        match self.entry(k) {
+            Entry::Occupied(mut e) => Some(e.insert(v)),
+            Entry::Vacant(e) => {
+                e.insert(v);
+                None
+            },
+        }
+    }
+    pub fn entry(&mut self, k: K) -> Entry<'_,K,V,C> {
+        let pos = match self.multy_contains(&k) {
+            Some((msi,idx)) => Some(RunPos::Level(msi,idx)),
+            None => self.slot.contains(&k,&self.cmp).map(RunPos::Slot),
+        };
+        match pos {
+            Some(pos) => Entry::Occupied(OccupiedEntry { map: self, pos }),
+            None => Entry::Vacant(VacantEntry { map: self, key: k }),
+        }
+    }
+    fn push_new(&mut self, k: K, v: V) -> &mut V {
+        // full slot is merged before the push, so the new value always stays in the slot
+        if let Filled::Full = self.slot.filled() {
+            self.merge_slot();
+        }
+        self.len += 1;
+        self.slot.data.push((k,v));
+        let idx = self.slot.data.len() - 1;
+        &mut self.slot.data[idx].1
+    }
+    fn merge_slot(&mut self) {
+        if self.data.len() == 0 {
+            self.data.push(self.slot.into_map_multislot(&self.cmp));
+        } else {
+            let levels: Vec<LevelInfo> = self.data.iter().map(|ms| LevelInfo {
+                capacity: ms.capacity,
+                len: ms.flags.rank(ms.keys.len()),
+            }).collect();
+            let range = self.policy.merge_range(self.slot.len(),&levels);
+            let to = (*range.end()).min(levels.len());
+            let from = (*range.start()).min(to);
+            match (from == 0) && self.data.get(to).is_none_or(|ms| ms.empty()) && self.data[..to].iter().all(|ms| !ms.empty()) {
+                true => {
+                    if to == self.data.len() {
+                        self.data.push(MapMultiSlot::new_empty(self.level_capacity(to)));
+                    }
+                    if let Err(s) = self.merge_into(to) {
+                        panic!("Unreachable merge_into: {}",s);
+                    }
+                    if let Err(s) = self.check_tombs(to) {
+                        panic!("Unreachable check_tombs: {}",s);
+                    }
+                },
+                false => {
+                    // an empty level fits the slot and everything below it, so there is always a place
+                    let to = (to + 1).min(levels.len());
+                    let cnt = self.slot.len() + levels[from .. to].iter().map(|l| l.len).sum::<usize>();
+                    let mut n = from;
+                    while (n < self.data.len()) && ((self.data[n].capacity < cnt) || ((n >= to) && !self.data[n].empty())) { n += 1; }
+                    if n == self.data.len() {
+                        self.data.push(MapMultiSlot::new_unallocated(self.level_capacity(n)));
+                    }
+                    self.merge_levels(from .. to,n);
+                },
             }
+            self.shrink_long();
         }
-        if r.is_none() {
-            self.len += 1;
+    }
+    fn merge_levels(&mut self, range: Range<usize>, target: usize) {
+        // the slot and the live entries of data[range] are merged into data[target],
+        //   levels of the range may be empty, target is either one of them or an empty level
+        let cmp = &self.cmp;
+        let mut data: Vec<(K,V)> = self.slot.sorted_drain(cmp).collect();
+        self.slot.clear();
+        for ms in &mut self.data[range] {
+            if ms.empty() { continue; }
+            self.tombs -= ms.capacity - ms.flags.rank(ms.keys.len());
+            let run = ms.filtered_drain().collect();
+            data = merge_sorted(data,run,|a,b|cmp.compare(&a.0,&b.0),|a,_|a);
+            ms.clear();
         }
-        r
+        let ms = &mut self.data[target];
+        ms.reserve(data.len());
+        for (k,v) in data {
+            ms.keys.push(k);
+            ms.values.push(v);
+        }
+        let c = ms.keys.len();
+        ms.flags.set_ones(c);
+        self.tombs += ms.capacity - c;
     }
     pub fn len(&self) -> usize {
         self.len
@@ -462,14 +1070,18 @@ impl<K: Ord, V> CivMap<K,V> {
     pub fn tombs(&self) -> usize {
         self.tombs
     }
-    pub fn remove(&mut self, k: &K) -> Option<RemovedItem<V>> {
-        let r = match self.multy_contains(&k) {
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<RemovedItem<V>> where K: Borrow<Q>, C: Comparator<Q> {
+        let r = match self.multy_contains(k) {
             Some((msi,idx)) => {
                 self.tombs += 1;
                 self.data[msi].flags.unset(idx);
-                Some(RemovedItem::Ref(&mut self.data[msi].values[idx]))
+                match self.overfull(msi) {
+                    // the level is rewritten, so the removed value is moved out of it
+                    true => self.purge_level(msi,Some(idx)).map(|(_,v)| RemovedItem::Owned(v)),
+                    false => Some(RemovedItem::Ref(&mut self.data[msi].values[idx])),
+                }
             },
-            None => match self.slot.remove(k) {
+            None => match self.slot.remove(k,&self.cmp) {
                 Some(v) => Some(RemovedItem::Owned(v)),
                 None => None,
             },
@@ -479,6 +1091,137 @@ impl<K: Ord, V> CivMap<K,V> {
         }
         r
     }
+    pub fn remove_take<Q: ?Sized>(&mut self, k: &Q) -> Option<V> where K: Borrow<Q>, C: Comparator<Q>, V: Default {
+        self.remove(k).map(RemovedItem::take)
+    }
+    pub fn retain<F: FnMut(&K,&mut V) -> bool>(&mut self, mut f: F) {
+        // removed level entries become tombstones, their bits are cleared in bulk
+        self.slot.data.retain_mut(|(k,v)| f(k,v));
+        for ms in &mut self.data {
+            let keys = &ms.keys;
+            let values = &mut ms.values;
+            ms.flags.retain(keys.len(),|idx| f(&keys[idx],&mut values[idx]));
+        }
+        self.recount();
+        self.check_levels();
+    }
+    fn overfull(&self, msi: usize) -> bool {
+        // removed entries are a notable part of the level
+        let n = self.data[msi].keys.len();
+        let dead = n - self.data[msi].flags.rank(n);
+        (dead > self.slot.max_size()) && ((dead as f64) / (n as f64) > self.config.tombs_limit())
+    }
+    fn check_levels(&mut self) {
+        // a level moved down is already purged, so the levels are checked bottom-up
+        for msi in 0 .. self.data.len() {
+            if self.overfull(msi) {
+                self.purge_level(msi,None);
+            }
+        }
+    }
+    fn purge_level(&mut self, msi: usize, take: Option<usize>) -> Option<(K,V)> {
+        // tombstones of data[msi] are dropped, the rest moves to the smallest empty level below that fits it;
+        //   the entry at take is already unset and is returned
+        let n = self.data[msi].keys.len();
+        let live = self.data[msi].flags.rank(n);
+        let to = (0 .. msi).find(|&j| self.data[j].empty() && (self.data[j].capacity >= live)).unwrap_or(msi);
+        let cap = self.data[msi].capacity;
+        let keys = std::mem::take(&mut self.data[msi].keys);
+        let values = std::mem::take(&mut self.data[msi].values);
+        let mut flags = std::mem::replace(&mut self.data[msi].flags,Flags::tmp());
+        let mut res = None;
+        self.data[to].reserve(live);
+        for (idx,(k,v)) in keys.into_iter().zip(values).enumerate() {
+            if take == Some(idx) {
+                res = Some((k,v));
+            } else if flags.get(idx) {
+                self.data[to].keys.push(k);
+                self.data[to].values.push(v);
+            }
+        }
+        if to == msi {
+            flags.set_nulls();
+            self.data[msi].flags = flags;
+        }
+        // the vacancy moves with the entries, an emptied level has none
+        let left = match live {
+            0 => 0,
+            _ => {
+                self.data[to].flags.set_ones(live);
+                self.data[to].capacity
+            },
+        };
+        self.tombs -= cap - left;
+        res
+    }
+    fn check_vacant(&mut self) {
+        // levels the entries were moved out of keep their capacity, so a level goes down
+        //   once it is too vacant
+        for msi in 0 .. self.data.len() {
+            let ms = &self.data[msi];
+            if ms.empty() { continue; }
+            let live = ms.flags.rank(ms.keys.len());
+            let vacant = ms.capacity - live;
+            let over = (vacant > self.slot.max_size()) && ((vacant as f64) / (ms.capacity as f64) > self.config.tombs_limit());
+            if live == 0 {
+                self.purge_level(msi,None);
+            } else if over {
+                // it is merged with the levels below into the lowest level that fits them all,
+                //   which is spread over the empty levels below as after an insert when it is too vacant
+                let cnt = self.slot.len() + self.data[..= msi].iter().map(|ms| ms.flags.rank(ms.keys.len())).sum::<usize>();
+                let n = (0 ..= msi).find(|&n| self.data[n].capacity >= cnt).unwrap_or(msi + 1);
+                if self.data.get(n).is_none_or(|ms| (n <= msi) || ms.empty()) {
+                    if n == self.data.len() {
+                        self.data.push(MapMultiSlot::new_unallocated(self.level_capacity(n)));
+                    }
+                    self.merge_levels(0 .. msi+1,n);
+                    if let Err(s) = self.check_tombs(n) {
+                        panic!("Unreachable check_tombs: {}",s);
+                    }
+                }
+            }
+        }
+    }
+    fn dead(&self) -> usize {
+        // removed entries still stored in the levels
+        self.data.iter().fold(0,|acc,ms| acc + ms.keys.len() - ms.flags.rank(ms.keys.len()))
+    }
+    pub fn compact(&mut self) {
+        // slot and levels are merged into a single tombstone-free level, unused memory is given back
+        let (keys,values) = self.take_sorted().into_iter().unzip();
+        self.load_sorted(keys,values);
+        self.shrink_to_fit();
+        self.data.shrink_to_fit();
+        self.tmp_merge_keys.shrink_to_fit();
+        self.tmp_merge_values.shrink_to_fit();
+    }
+    pub fn compact_if(&mut self, ratio: f64) -> bool {
+        // compacts if tombstones are more than ratio of the stored entries
+        let dead = self.dead();
+        match (dead > 0) && ((dead as f64) / ((self.len + dead) as f64) > ratio) {
+            true => {
+                self.compact();
+                true
+            },
+            false => false,
+        }
+    }
+    pub fn remove_range<Q: ?Sized, R: RangeBounds<Q>>(&mut self, range: R) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        // the span of every level is found by binary search and its bits are cleared word-at-a-time
+        let cmp = &self.cmp;
+        let before = self.slot.len();
+        self.slot.data.retain(|(k,_)| !in_range(k.borrow(),&range,cmp));
+        let mut cnt = before - self.slot.len();
+        for ms in &mut self.data {
+            let (from,to) = range_span(&ms.keys,&range,cmp);
+            let c = ms.flags.unset_span(from,to);
+            self.tombs += c;
+            cnt += c;
+        }
+        self.len -= cnt;
+        self.check_levels();
+        cnt
+    }
     pub fn shrink_to_fit(&mut self) {
         for ms in &mut self.data {
             ms.shrink_to_fit();
@@ -486,7 +1229,7 @@ impl<K: Ord, V> CivMap<K,V> {
     }
     fn shrink_long(&mut self) {
         for ms in &mut self.data {
-            if (ms.capacity >= AUTO_SHRINK_LIMIT)&&(ms.empty()) {   
+            if (ms.capacity >= self.config.shrink_limit())&&(ms.empty()) {   
                 ms.shrink_to_fit();
             }
         }
@@ -500,7 +1243,9 @@ impl<K: Ord, V> CivMap<K,V> {
         let sz =  self.slot.max_size();
         let local_tombs = self.data[n].capacity - self.data[n].keys.len();
         let local_part = (local_tombs as f64) / (self.data[n].capacity as f64);
-        if (local_tombs > sz) && (local_part > TOMBS_LIMIT) {
+        let mut below: usize = self.data[..n].iter().map(|ms| ms.capacity).sum();
+        // with a growth factor above 2 the data may not fit the levels below
+        if (local_tombs > sz) && (local_part > self.config.tombs_limit()) && (self.data[n].keys.len() <= below) {
             std::mem::swap(&mut self.data[n].keys, &mut self.tmp_merge_keys);
             std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
             {
@@ -510,6 +1255,7 @@ impl<K: Ord, V> CivMap<K,V> {
                 let mut msi = self.data[..n].iter_mut();
                 while let Some(ms) = msi.next_back() {
                     let cap = ms.capacity;
+                    below -= cap;
                     if count >= cap {
                         for _ in 0 .. cap {
                             if let Some((k,v)) = iter.next() {
@@ -522,11 +1268,19 @@ impl<K: Ord, V> CivMap<K,V> {
                         }
                         ms.flags.set_ones(cap);
                         count -= cap;
-                        if count == 0 { break; }
+                        if count == 0 {
+                            // all the levels are full, the vacancy of data[n] is gone
+                            if local_tombs > self.tombs {
+                                return Err("local_tombs > self.tombs");
+                            }
+                            self.tombs -= local_tombs;
+                            break;
+                        }
                         continue;
                     }
-                    if (cap - count) > sz { continue; }
-                    // checked tombs = (cap - count) <= sz and local_tombs > sz
+                    // the rest goes lower unless it does not fit there
+                    if ((cap - count) > sz) && (count <= below) { continue; }
+                    // checked tombs = (cap - count) <= local_tombs, data[n] fits all the levels below
                     let d_tombs = local_tombs - (cap - count);
                     for _ in 0 .. count {
                         if let Some((k,v)) = iter.next() {
@@ -558,7 +1312,8 @@ impl<K: Ord, V> CivMap<K,V> {
         // merge sort for sorted inflating vectors
         
         if !self.data[n].empty() { return Err("data[n] is not empty"); }
-        let mut cnt = self.slot.len();
+        let slot_len = self.slot.len();
+        let mut cnt = slot_len;
         for i in 0 .. n {
             if self.data[i].empty() { return Err("one of data[0..n] is empty"); }
             cnt += self.data[i].keys.len();
@@ -569,13 +1324,13 @@ impl<K: Ord, V> CivMap<K,V> {
         std::mem::swap(&mut self.data[n].values, &mut self.tmp_merge_values);
         {
             if n == 0 {
-                for (k,v) in self.slot.sorted_drain() {
+                for (k,v) in self.slot.sorted_drain(&self.cmp) {
                     self.tmp_merge_keys.push(k);
                     self.tmp_merge_values.push(v);
                 }
                 self.slot.clear();
             } else {
-                let mut slot = self.slot.into_map_multislot();
+                let mut slot = self.slot.into_map_multislot(&self.cmp);
                 self.slot.clear();
                 for i in 0 .. n {
                     { // for split_at_mut
@@ -592,7 +1347,7 @@ impl<K: Ord, V> CivMap<K,V> {
                             while f.is_some() && s.is_some() {
                                 let fe = f.take().unwrap(); // safe
                                 let se = s.take().unwrap(); // safe
-                                match fe.0 < se.0 {
+                                match self.cmp.compare(&fe.0,&se.0) == Ordering::Less {
                                     true => {
                                         self.tmp_merge_keys.push(fe.0);
                                         self.tmp_merge_values.push(fe.1);
@@ -671,12 +1426,146 @@ impl<K: Ord, V> CivMap<K,V> {
    
         let c = self.data[n].keys.len();
         self.data[n].flags.set_ones(c);
+        // the capacity of data[n] above the slot and data[0..n] is vacant
+        let below: usize = self.data[..n].iter().map(|ms| ms.capacity).sum();
+        self.tombs += self.data[n].capacity - below - slot_len;
         Ok(())
     }
 }
 
+impl<K: Clone, V: Default, C: Comparator<K>> CivMap<K,V,C> {
+    // level entries stay in place as tombstones: the key handed out is a clone of the stored one
+    //   and the value is taken out like in remove_take; tombstones are reclaimed by the purge of an overfull level
+    pub fn pop_first(&mut self) -> Option<(K,V)> {
+        let pos = self.runs().next().map(|(_,pos)| pos)?;
+        Some(self.take_at(pos))
+    }
+    pub fn pop_last(&mut self) -> Option<(K,V)> {
+        let pos = self.runs().next_back().map(|(_,pos)| pos)?;
+        Some(self.take_at(pos))
+    }
+    pub fn extract_if<F: FnMut(&K,&mut V) -> bool>(&mut self, pred: F) -> ExtractIf<'_,K,V,C,F> {
+        // lazy: entries are visited as they are stored, the slot first and then the levels from the smallest,
+        //   so matching entries come out in no particular order; entries not reached by the iterator are kept
+        ExtractIf {
+            map: self,
+            pred,
+            slot_idx: 0,
+            msi: 0,
+            idx: 0,
+        }
+    }
+    fn take_at(&mut self, pos: RunPos) -> (K,V) {
+        self.len -= 1;
+        match pos {
+            RunPos::Slot(idx) => self.slot.data.swap_remove(idx),
+            RunPos::Level(msi,idx) => {
+                self.tombs += 1;
+                self.data[msi].flags.unset(idx);
+                if self.overfull(msi) {
+                    if let Some(kv) = self.purge_level(msi,Some(idx)) { return kv; }
+                }
+                let ms = &mut self.data[msi];
+                (ms.keys[idx].clone(),std::mem::take(&mut ms.values[idx]))
+            },
+        }
+    }
+}
+
+impl<K, V, C: Comparator<K> + Default> Default for CivMap<K,V,C> {
+    fn default() -> CivMap<K,V,C> {
+        CivMap::with_comparator(C::default())
+    }
+}
+impl<K, V, C: Comparator<K> + Default> FromIterator<(K,V)> for CivMap<K,V,C> {
+    fn from_iter<I: IntoIterator<Item = (K,V)>>(iter: I) -> CivMap<K,V,C> {
+        let mut map = CivMap::default();
+        map.load_unsorted(iter.into_iter().collect());
+        map
+    }
+}
+impl<K, V, C: Comparator<K>> Extend<(K,V)> for CivMap<K,V,C> {
+    fn extend<I: IntoIterator<Item = (K,V)>>(&mut self, iter: I) {
+        let batch: Vec<(K,V)> = iter.into_iter().collect();
+        if batch.len() < self.slot.max_size() {
+            for (k,v) in batch {
+                self.insert(k,v);
+            }
+            return;
+        }
+        // only the batch is sorted, then it is merged into the levels
+        let batch = self.sort_unique(batch);
+        self.replace_run(batch);
+    }
+}
+impl<K: Borrow<Q>, Q: ?Sized, V, C: Comparator<K> + Comparator<Q>> std::ops::Index<&Q> for CivMap<K,V,C> {
+    type Output = V;
+    fn index(&self, k: &Q) -> &V {
+        self.get(k).expect("key not found")
+    }
+}
+
+// Comparisons and hashing go over the sorted live entries,
+//   so the slot and level layout does not matter
+impl<K: PartialEq, V: PartialEq, C: Comparator<K>> PartialEq for CivMap<K,V,C> {
+    fn eq(&self, other: &CivMap<K,V,C>) -> bool {
+        (self.len == other.len) && self.iter_sorted().eq(other.iter_sorted())
+    }
+}
+impl<K: Eq, V: Eq, C: Comparator<K>> Eq for CivMap<K,V,C> {}
+impl<K: PartialOrd, V: PartialOrd, C: Comparator<K>> PartialOrd for CivMap<K,V,C> {
+    fn partial_cmp(&self, other: &CivMap<K,V,C>) -> Option<Ordering> {
+        self.iter_sorted().partial_cmp(other.iter_sorted())
+    }
+}
+impl<K: Ord, V: Ord, C: Comparator<K>> Ord for CivMap<K,V,C> {
+    fn cmp(&self, other: &CivMap<K,V,C>) -> Ordering {
+        self.iter_sorted().cmp(other.iter_sorted())
+    }
+}
+impl<K: Hash, V: Hash, C: Comparator<K>> Hash for CivMap<K,V,C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for kv in self.iter_sorted() {
+            kv.hash(state);
+        }
+    }
+}
+impl<K: PartialEq, V: PartialEq, C: Comparator<K>> PartialEq<BTreeMap<K,V>> for CivMap<K,V,C> {
+    fn eq(&self, other: &BTreeMap<K,V>) -> bool {
+        (self.len == other.len()) && self.iter_sorted().eq(other.iter())
+    }
+}
+impl<K: PartialEq, V: PartialEq, C: Comparator<K>> PartialEq<CivMap<K,V,C>> for BTreeMap<K,V> {
+    fn eq(&self, other: &CivMap<K,V,C>) -> bool {
+        other == self
+    }
+}
+
+impl<K: Ord, V> From<BTreeMap<K,V>> for CivMap<K,V> {
+    fn from(map: BTreeMap<K,V>) -> CivMap<K,V> {
+        // already sorted and unique
+        CivMap::from_sorted_iter_unchecked(map)
+    }
+}
+impl<K: Ord, V, S> From<HashMap<K,V,S>> for CivMap<K,V> {
+    fn from(map: HashMap<K,V,S>) -> CivMap<K,V> {
+        CivMap::from_unsorted_vec(map.into_iter().collect())
+    }
+}
+impl<K: Ord, V, C: Comparator<K>> From<CivMap<K,V,C>> for BTreeMap<K,V> {
+    fn from(mut map: CivMap<K,V,C>) -> BTreeMap<K,V> {
+        map.take_sorted().into_iter().collect()
+    }
+}
+impl<K: Eq + Hash, V, C: Comparator<K>, S: BuildHasher + Default> From<CivMap<K,V,C>> for HashMap<K,V,S> {
+    fn from(mut map: CivMap<K,V,C>) -> HashMap<K,V,S> {
+        map.take_all().into_iter().collect()
+    }
+}
+
 #[cfg(feature = "debug")]
-impl<K: Ord, V> CivMap<K,V> {
+impl<K, V, C: Comparator<K>> CivMap<K,V,C> {
     pub fn check_len(&self) -> usize {
         self.slot.len() + self.data.iter().fold(0,|acc,x|acc+x.check_len())
     }
@@ -708,9 +1597,21 @@ impl<K: Ord, V> CivMap<K,V> {
     }
 }
 
+#[cfg(test)]
+impl<K, V, C: Comparator<K>> CivMap<K,V,C> {
+    fn check_invariants(&self) {
+        // the stored counters against a recount of the slot and the levels
+        let live = self.slot.len() + self.data.iter().fold(0,|acc,ms| acc + ms.flags.rank(ms.keys.len()));
+        let tombs = self.data.iter().filter(|ms| !ms.empty()).fold(0,|acc,ms| acc + ms.capacity - ms.flags.rank(ms.keys.len()));
+        assert_eq!(live,self.len());
+        assert_eq!(tombs,self.tombs());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::civs::{Tiered,Leveled};
     
     #[test]
     #[ignore]
@@ -759,6 +1660,629 @@ mod tests {
         panic!();
     }
 
+    #[test]
+    fn test_iter_sorted() {
+        let cnt = 100_000;
+        let mut res = Vec::with_capacity(cnt);
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        for i in 0 .. cnt {
+            let k = ((i * 7919) % cnt) as u64;
+            map.insert(k, k as u32);
+        }
+        for i in 0 .. cnt {
+            match i % 10 {
+                0 => { map.remove(&(i as u64)); },
+                _ => res.push((i as u64, i as u32)),
+            }
+        }
+
+        let lib = map.iter_sorted().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+        assert_eq!(res,lib);
+
+        res.reverse();
+        let lib = map.iter_sorted().rev().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+        assert_eq!(res,lib);
+    }
+
+    #[test]
+    fn test_range() {
+        use std::collections::BTreeMap;
+        use std::ops::Bound;
+
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        let mut ctr = BTreeMap::new();
+        for i in 0 .. 10_000u64 {
+            let k = (i * 7919) % 20_000;
+            map.insert(k, i as u32);
+            ctr.insert(k, i as u32);
+        }
+        for i in (0 .. 20_000u64).step_by(3) {
+            map.remove(&i);
+            ctr.remove(&i);
+        }
+        let bounds = [
+            (Bound::Included(100),Bound::Excluded(5_000)),
+            (Bound::Excluded(100),Bound::Included(5_000)),
+            (Bound::Unbounded,Bound::Included(77)),
+            (Bound::Included(19_990),Bound::Unbounded),
+            (Bound::Included(500),Bound::Excluded(500)),
+            (Bound::Unbounded,Bound::Unbounded),
+        ];
+        for b in bounds.iter() {
+            let lib = map.range(*b).map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            let res = ctr.range(*b).map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            assert_eq!(res,lib);
+            let lib = map.range(*b).rev().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            let res = ctr.range(*b).rev().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            assert_eq!(res,lib);
+        }
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        for i in 0 .. 5_000u64 {
+            map.insert(i * 10, i as u32);
+        }
+        for i in (0 .. 5_000u64).step_by(2) {
+            map.remove(&(i * 10));
+        }
+
+        assert_eq!(map.floor(&25),Some((&10,&1)));
+        assert_eq!(map.floor(&30),Some((&30,&3)));
+        assert_eq!(map.floor(&9),None);
+        assert_eq!(map.ceiling(&25),Some((&30,&3)));
+        assert_eq!(map.ceiling(&49_991),None);
+
+        let mut cursor = map.cursor();
+        assert_eq!(cursor.peek_prev(),None);
+        cursor.seek(&1_000);
+        assert_eq!(cursor.peek_next(),Some((&1_010,&101)));
+        assert_eq!(cursor.next(),Some((&1_010,&101)));
+        assert_eq!(cursor.next(),Some((&1_030,&103)));
+        assert_eq!(cursor.prev(),Some((&1_030,&103)));
+        assert_eq!(cursor.prev(),Some((&1_010,&101)));
+        assert_eq!(cursor.prev(),Some((&990,&99)));
+        cursor.seek_last();
+        assert_eq!(cursor.next(),None);
+        assert_eq!(cursor.prev(),Some((&49_990,&4_999)));
+        cursor.seek_first();
+        assert_eq!(cursor.count(),2_500);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        for i in 0 .. 1_000u64 {
+            map.insert(i, i as u32);
+        }
+        for i in 0 .. 300u64 {
+            map.remove(&i);
+        }
+        assert_eq!(map.first(),Some((&300,&300)));
+        assert_eq!(map.last(),Some((&999,&999)));
+        assert_eq!(map.pop_first(),Some((300,300)));
+        assert_eq!(map.pop_last(),Some((999,999)));
+        assert_eq!(map.len(),698);
+        let mut cnt = 0;
+        while let Some((k,v)) = map.pop_first() {
+            assert_eq!(k,301 + cnt);
+            assert_eq!(v,k as u32);
+            cnt += 1;
+        }
+        assert_eq!(cnt,698);
+        assert_eq!(map.len(),0);
+        assert_eq!(map.first(),None);
+
+        // the values are taken out of their tombstones, not cloned
+        #[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+        struct Key(u64);
+        let mut map: CivMap<Key,Vec<u8>> = CivMap::new();
+        for i in 0 .. 1_000u64 {
+            map.insert(Key(i),vec![0; 10]);
+        }
+        for i in (0 .. 1_000u64).step_by(3) {
+            map.remove(&Key(i));
+        }
+        assert_eq!(map.pop_first(),Some((Key(1),vec![0; 10])));
+        assert_eq!(map.pop_last(),Some((Key(998),vec![0; 10])));
+        assert_eq!(map.pop_last().map(|(k,_)|k),Some(Key(997)));
+        assert_eq!(map.len(),663);
+        assert!(map.iter_sorted().map(|(k,_)|k.0).eq((2 .. 997u64).filter(|i| i % 3 != 0)));
+        map.check_invariants();
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        for i in 0 .. 20_000u64 {
+            map.insert((i * 7919) % 20_000, i as u32);
+        }
+        for i in (0 .. 20_000u64).filter(|i| (i % 3 == 0)||(i % 1000 < 200)) {
+            map.remove(&i);
+        }
+        let sorted = map.iter_sorted().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+        for (n,(k,v)) in sorted.iter().enumerate() {
+            assert_eq!(map.rank(k),n);
+            assert_eq!(map.select(n),Some((k,v)));
+        }
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let map: CivMap<u64,u32> = CivMap::from_reader(&buf[..]).unwrap();
+        for (n,(k,_)) in sorted.iter().enumerate().step_by(97) {
+            assert_eq!(map.rank(k),n);
+        }
+        assert_eq!(map.rank(&0),0);
+        assert_eq!(map.rank(&20_000),sorted.len());
+        assert_eq!(map.select(sorted.len()),None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut map: CivMap<u64,u32> = CivMap::new();
+        for i in 0 .. 10_000u64 {
+            *map.entry(i % 1_000).or_default() += 1;
+        }
+        assert_eq!(map.len(),1_000);
+        for i in 0 .. 1_000u64 {
+            assert_eq!(map.get(&i),Some(&10));
+        }
+
+        map.entry(5).and_modify(|v| *v = 50).or_insert(0);
+        map.entry(5_000).and_modify(|v| *v = 50).or_insert(7);
+        assert_eq!(map.get(&5),Some(&50));
+        assert_eq!(map.get(&5_000),Some(&7));
+
+        match map.entry(17) {
+            Entry::Occupied(e) => assert_eq!(e.remove().copied(),10),
+            Entry::Vacant(_) => panic!("key 17 must be occupied"),
+        }
+        match map.entry(5_000) {
+            Entry::Occupied(e) => assert_eq!(e.remove().copied(),7),
+            Entry::Vacant(_) => panic!("key 5000 must be occupied"),
+        }
+        assert_eq!(map.len(),999);
+        assert!(!map.contains(&17));
+        assert_eq!(*map.entry(17).or_insert_with(|| 3),3);
+        assert_eq!(map.len(),1_000);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        use std::collections::BTreeMap;
+
+        assert!(matches!(CivMap::from_sorted_iter(vec![(1u64,1u32),(3,3),(2,2)]),Err(CivMapBuildError::Unsorted(2))));
+        assert!(matches!(CivMap::from_sorted_iter(vec![(1u64,1u32),(1,1)]),Err(CivMapBuildError::Duplicate(1))));
+
+        for cnt in [10u64,64,1_000,100_000].iter() {
+            let mut map = CivMap::from_sorted_iter((0 .. *cnt).map(|i| (i * 2, i as u32))).unwrap();
+            let mut ctr = (0 .. *cnt).map(|i| (i * 2, i as u32)).collect::<BTreeMap<_,_>>();
+            assert_eq!(map.len(),ctr.len());
+            for i in 0 .. 10_000u64 {
+                let k = (i * 7919) % (3 * cnt);
+                assert_eq!(map.insert(k,i as u32),ctr.insert(k,i as u32));
+                if i % 3 == 0 {
+                    assert_eq!(map.remove(&(k/2)).map(|v| v.copied()),ctr.remove(&(k/2)));
+                }
+            }
+            let lib = map.iter_sorted().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            let res = ctr.iter().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+            assert_eq!(res,lib);
+        }
+
+        let map = CivMap::from_unsorted_vec(vec![(5u64,1u32),(3,1),(5,2),(1,1),(3,2),(5,3)]);
+        assert_eq!(map.iter_sorted().map(|(k,v)| (*k,*v)).collect::<Vec<_>>(),vec![(1,1),(3,2),(5,3)]);
+    }
+
+    #[test]
+    fn test_collect_extend() {
+        use std::collections::BTreeMap;
+
+        let mut map = (0 .. 1_000u64).map(|i| (i * 3, i as u32)).collect::<CivMap<_,_>>();
+        let mut ctr = (0 .. 1_000u64).map(|i| (i * 3, i as u32)).collect::<BTreeMap<_,_>>();
+        assert_eq!(map[&30],10);
+
+        map.extend((0 .. 10u64).map(|i| (i * 5, 0)));
+        ctr.extend((0 .. 10u64).map(|i| (i * 5, 0)));
+        map.extend((0 .. 5_000u64).map(|i| (i * 2, 1)));
+        ctr.extend((0 .. 5_000u64).map(|i| (i * 2, 1)));
+        assert_eq!(map.len(),ctr.len());
+        let lib = map.iter_sorted().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+        let res = ctr.iter().map(|(k,v)| (*k,*v)).collect::<Vec<_>>();
+        assert_eq!(res,lib);
+
+        // a batch smaller than the map is merged into the lowest levels only,
+        //   present keys get their new values in place
+        let top = map.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = map.data[top].keys.as_ptr();
+        map.extend((0 .. 1_000u64).rev().map(|i| (i * 7, 2)));
+        ctr.extend((0 .. 1_000u64).rev().map(|i| (i * 7, 2)));
+        assert_eq!(map.data[top].keys.as_ptr(),ptr);
+        assert_eq!(map,ctr);
+
+        let map: CivMap<u64,u32> = Default::default();
+        assert_eq!(map.len(),0);
+    }
+
+    #[test]
+    fn test_remove_take() {
+        let mut map: CivMap<u64,Vec<u8>> = CivMap::new();
+        for i in 0 .. 1_000u64 {
+            map.insert(i, vec![0; 100]);
+        }
+        for i in (0 .. 1_000u64).step_by(2) {
+            assert_eq!(map.remove_take(&i).map(|v| v.len()),Some(100));
+        }
+        assert_eq!(map.remove_take(&0),None);
+        assert_eq!(map.len(),500);
+        let held = map.data.iter().flat_map(|ms| ms.values.iter()).filter(|v| v.capacity() > 0).count();
+        assert_eq!(held,map.len() - map.slot.len());
+        let (k,v) = map.pop_first().unwrap();
+        assert_eq!((k,v.len()),(1,100));
+    }
+
+    #[test]
+    fn test_borrow() {
+        let mut map: CivMap<String,u32> = CivMap::new();
+        for i in 0 .. 1_000u32 {
+            map.insert(format!("key{}",i), i);
+        }
+        assert!(map.contains("key10"));
+        assert_eq!(map.get("key500"),Some(&500));
+        assert_eq!(map["key999"],999);
+        *map.get_mut("key7").unwrap() += 1;
+        assert_eq!(map.remove_take("key7"),Some(8));
+        assert!(map.remove("key7").is_none());
+        assert_eq!(map.len(),999);
+        assert_eq!(map.range::<str,_>((Bound::Included("key10"),Bound::Excluded("key11"))).count(),11);
+        assert_eq!(map.ceiling("key99z"),None);
+        assert_eq!(map.rank("key1"),1);
+    }
+
+    #[test]
+    fn test_comparator() {
+        let mut map: CivMap<u64,u64,crate::ReverseOrder> = CivMap::with_comparator(crate::ReverseOrder);
+        for i in 0 .. 10_000u64 {
+            map.insert((i * 7919) % 10_000, i);
+        }
+        for i in 0 .. 10_000u64 {
+            if i % 3 == 0 { map.remove(&i); }
+        }
+        let keys: Vec<u64> = map.iter_sorted().map(|(k,_)|*k).collect();
+        let res: Vec<u64> = (0 .. 10_000u64).rev().filter(|i| i % 3 != 0).collect();
+        assert_eq!(keys,res);
+        assert_eq!(map.first().map(|(k,_)|*k),Some(9_998));
+        assert_eq!(map.floor(&5_001),Some((&5_002,map.get(&5_002).unwrap())));
+        assert_eq!(map.rank(&9_997),1);
+
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let loaded: CivMap<u64,u64,crate::ReverseOrder> = CivMap::from_reader(&buf[..]).unwrap();
+        assert!(loaded.iter_sorted().map(|(k,_)|*k).eq(res.into_iter()));
+    }
+
+    #[test]
+    fn test_absorb() {
+        use std::collections::BTreeMap;
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut other: CivMap<u64,u64> = CivMap::new();
+        let mut ctrl = BTreeMap::new();
+        for i in 0 .. 20_000u64 {
+            map.insert(i * 2, 1);
+            *ctrl.entry(i * 2).or_insert(0) += 1;
+        }
+        for i in 0 .. 20_000u64 {
+            if i % 5 == 0 {
+                map.remove(&(i * 2));
+                ctrl.remove(&(i * 2));
+            }
+        }
+        for i in 0 .. 15_000u64 {
+            other.insert(i * 3, 10);
+            *ctrl.entry(i * 3).or_insert(0) += 10;
+        }
+        map.absorb_with(other,|_,old,new| old + new);
+        assert_eq!(map.len(),ctrl.len());
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.iter().map(|(k,v)|(*k,*v))));
+        map.insert(1,0);
+        ctrl.insert(1,0);
+        assert_eq!(map.get(&1),Some(&0));
+
+        // a small map only rewrites the lowest levels, unless its keys are in the levels above
+        let top = map.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = map.data[top].keys.as_ptr();
+        let small: CivMap<u64,u64> = (60_000 .. 60_100u64).map(|i| (i,i)).collect();
+        map.absorb_with(small,|_,old,new| old + new);
+        assert_eq!(map.data[top].keys.as_ptr(),ptr);
+        let small: CivMap<u64,u64> = (0 .. 100u64).map(|i| (i,i)).collect();
+        map.absorb_with(small,|_,old,new| old + new);
+        for i in 0 .. 100u64 {
+            *ctrl.entry(i).or_insert(0) += i;
+        }
+        ctrl.extend((60_000 .. 60_100u64).map(|i| (i,i)));
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.into_iter()));
+    }
+
+    #[test]
+    fn test_split_append() {
+        use std::collections::BTreeMap;
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut ctrl = BTreeMap::new();
+        for i in 0 .. 100_000u64 {
+            let k = (i * 7919) % 100_000;
+            map.insert(k,i);
+            ctrl.insert(k,i);
+            if i % 7 == 0 {
+                map.remove(&(i / 2));
+                ctrl.remove(&(i / 2));
+            }
+        }
+        let mut right = map.split_off(&40_000);
+        let mut ctrl_right = ctrl.split_off(&40_000);
+        // the split levels do not stay vacant
+        for half in [&map,&right] {
+            assert!((half.tombs() as f64) <= half.config.tombs_limit() * (half.len() as f64));
+            half.check_invariants();
+        }
+        assert_eq!((map.len(),right.len()),(ctrl.len(),ctrl_right.len()));
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.clone().into_iter()));
+        assert!(right.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl_right.clone().into_iter()));
+        for i in 0 .. 20_000u64 {
+            map.insert(i * 2,0);
+            ctrl.insert(i * 2,0);
+            right.insert(50_000 + i,1);
+            ctrl_right.insert(50_000 + i,1);
+        }
+        assert!(right.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl_right.clone().into_iter()));
+        assert_eq!(map.split_off(&1_000_000).len(),0);
+
+        map.append(&mut right);
+        ctrl.append(&mut ctrl_right);
+        assert_eq!(right.len(),0);
+        assert_eq!(map.len(),ctrl.len());
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.clone().into_iter()));
+
+        // an ordered append goes into the lowest levels only
+        let top = map.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = map.data[top].keys.as_ptr();
+        let mut tail: CivMap<u64,u64> = (200_000 .. 205_000u64).map(|i|(i,i)).collect();
+        map.append(&mut tail);
+        ctrl.extend((200_000 .. 205_000u64).map(|i|(i,i)));
+        assert_eq!(tail.len(),0);
+        assert_eq!(map.data[top].keys.as_ptr(),ptr);
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.clone().into_iter()));
+
+        let mut other: CivMap<u64,u64> = (0 .. 1_000u64).map(|i|(i * 3,7)).collect();
+        map.append(&mut other);
+        for i in 0 .. 1_000u64 {
+            ctrl.insert(i * 3,7);
+        }
+        assert!(map.iter_sorted().map(|(k,v)|(*k,*v)).eq(ctrl.into_iter()));
+    }
+
+    #[test]
+    fn test_eq_hash_ord() {
+        use std::collections::hash_map::DefaultHasher;
+        fn hash<T: Hash>(t: &T) -> u64 {
+            let mut h = DefaultHasher::new();
+            t.hash(&mut h);
+            h.finish()
+        }
+        let mut a: CivMap<u64,u64> = CivMap::new();
+        for i in 0 .. 10_000u64 {
+            a.insert(i,i * 2);
+        }
+        for i in 0 .. 10_000u64 {
+            if i % 4 == 0 { a.remove(&i); }
+        }
+        let b: CivMap<u64,u64> = CivMap::from_sorted_iter((0 .. 10_000u64).filter(|i| i % 4 != 0).map(|i|(i,i * 2))).unwrap();
+        let ctrl: BTreeMap<u64,u64> = b.iter_sorted().map(|(k,v)|(*k,*v)).collect();
+        assert_eq!(a,b);
+        assert_eq!(a,ctrl);
+        assert_eq!(ctrl,b);
+        assert_eq!(hash(&a),hash(&b));
+        assert_eq!(a.cmp(&b),Ordering::Equal);
+
+        a.insert(1,0);
+        assert_ne!(a,b);
+        assert!(a < b);
+        a.insert(1,2);
+        a.insert(10_001,0);
+        assert!(a > b);
+        assert_ne!(a,ctrl);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let ctrl: BTreeMap<u64,u64> = (0 .. 10_000u64).map(|i|(i * 3,i)).collect();
+        let map = CivMap::from(ctrl.clone());
+        assert_eq!(map,ctrl);
+        assert_eq!(BTreeMap::from(map.clone()),ctrl);
+        let hmap: HashMap<u64,u64> = map.clone().into();
+        assert_eq!(hmap.len(),ctrl.len());
+        assert!(ctrl.iter().all(|(k,v)| hmap.get(k) == Some(v)));
+        assert_eq!(CivMap::from(hmap),map);
+
+        let mut map: CivMap<u64,u64,crate::ReverseOrder> = ctrl.iter().map(|(k,v)|(*k,*v)).collect();
+        map.remove(&0);
+        let mut ctrl = ctrl;
+        ctrl.remove(&0);
+        assert_eq!(BTreeMap::from(map),ctrl);
+    }
+
+    #[test]
+    fn test_retain_extract() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut ctrl = BTreeMap::new();
+        for i in 0 .. 50_000u64 {
+            map.insert(i,i);
+            ctrl.insert(i,i);
+        }
+        map.remove(&3);
+        ctrl.remove(&3);
+        map.retain(|k,v| {
+            *v += 1;
+            k % 100 != 0
+        });
+        ctrl.retain(|k,v| {
+            *v += 1;
+            k % 100 != 0
+        });
+        assert_eq!(map,ctrl);
+        map.retain(|k,_| k % 3 != 0);
+        ctrl.retain(|k,_| k % 3 != 0);
+        assert_eq!(map,ctrl);
+        assert!(map.tombs() < map.len());
+
+        let mut out: Vec<(u64,u64)> = map.extract_if(|k,_| k % 7 == 0).collect();
+        out.sort();
+        let ctrl_out: Vec<(u64,u64)> = ctrl.extract_if(..,|k,_| k % 7 == 0).collect();
+        assert_eq!(out,ctrl_out);
+        assert_eq!(map,ctrl);
+        // entries the iterator did not reach stay in the map
+        let first: Vec<(u64,u64)> = map.extract_if(|k,_| k % 5 == 0).take(100).collect();
+        assert_eq!(first.len(),100);
+        for (k,v) in &first {
+            assert_eq!(ctrl.remove(k),Some(*v));
+        }
+        assert_eq!(map,ctrl);
+        map.check_invariants();
+        for i in 0 .. 1_000u64 {
+            assert_eq!(map.insert(i * 7,0),ctrl.insert(i * 7,0));
+        }
+        assert_eq!(map,ctrl);
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut map: CivMap<u64,u64> = CivMap::new();
+        let mut ctrl = BTreeMap::new();
+        for i in 0 .. 30_000u64 {
+            let k = i * 7919 % 30_000;
+            map.insert(k,i);
+            ctrl.insert(k,i);
+        }
+        map.remove(&1_000);
+        ctrl.remove(&1_000);
+        assert_eq!(map.remove_range(990 .. 2_010),1_019);
+        ctrl.retain(|k,_| !(990 .. 2_010).contains(k));
+        assert_eq!(map,ctrl);
+        assert_eq!(map.remove_range((Bound::Excluded(5_000),Bound::Included(5_001))),1);
+        ctrl.remove(&5_001);
+        assert_eq!(map.remove_range(29_990 ..),10);
+        ctrl.retain(|k,_| *k < 29_990);
+        assert_eq!(map.remove_range(10 .. 10),0);
+        assert_eq!(map,ctrl);
+        assert_eq!(map.range(900 .. 2_100).count(),ctrl.range(900 .. 2_100).count());
+        for i in 0 .. 3_000u64 {
+            assert_eq!(map.insert(i,0),ctrl.insert(i,0));
+        }
+        assert_eq!(map,ctrl);
+        assert_eq!(map.remove_range(..),ctrl.len());
+        assert_eq!(map.len(),0);
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(CivConfig::builder().growth_factor(1).build(),Err(crate::CivConfigError::GrowthFactor(1)));
+        assert_eq!(CivConfig::builder().slot_size(0).build(),Err(crate::CivConfigError::SlotSize(0)));
+        let config = CivConfig::builder().slot_size(16).growth_factor(4).tombs_limit(0.2).build().unwrap();
+        let mut map: CivMap<u64,u64> = CivMap::with_config(config);
+        let mut ctrl = BTreeMap::new();
+        for i in 0 .. 100_000u64 {
+            let k = i * 7919 % 20_000;
+            match i % 3 {
+                0 => assert_eq!(map.remove(&k).map(|v|*v.as_ref()),ctrl.remove(&k)),
+                _ => assert_eq!(map.insert(k,i),ctrl.insert(k,i)),
+            }
+            if i % 1_000 == 0 {
+                map.check_invariants();
+            }
+        }
+        assert_eq!(map,ctrl);
+        assert!(map.data.iter().enumerate().all(|(i,ms)| ms.capacity == 16 << (2 * i)));
+
+        let mut buf = Vec::new();
+        map.into_writer(&mut buf).unwrap();
+        let loaded: CivMap<u64,u64> = CivMap::from_reader(&buf[..]).unwrap();
+        assert_eq!(loaded.config(),&config);
+        assert_eq!(loaded,ctrl);
+        let right = map.split_off(&10_000);
+        assert_eq!(right.config(),&config);
+
+        // version 0.1 has no config
+        let map: CivMap<u64,u64> = (0 .. 1_000u64).map(|i|(i,i)).collect();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"CIVM");
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        bincode::serialize_into(&mut buf,&map.slot).unwrap();
+        bincode::serialize_into(&mut buf,&map.data).unwrap();
+        let loaded: CivMap<u64,u64> = CivMap::from_reader(&buf[..]).unwrap();
+        assert_eq!(loaded.config(),&CivConfig::default());
+        assert_eq!(loaded,map);
+
+        // a stored config goes through the checks of the builder
+        let read_bad = |config: CivConfig| {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(b"CIVM");
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&2u32.to_le_bytes());
+            bincode::serialize_into(&mut buf,&config).unwrap();
+            bincode::serialize_into(&mut buf,&map.slot).unwrap();
+            bincode::serialize_into(&mut buf,&map.data).unwrap();
+            let Err(CivMapIoError::InvalidConfig(e)) = CivMap::<u64,u64>::from_reader(&buf[..]) else { panic!("config is not checked") };
+            e
+        };
+        assert_eq!(read_bad(CivConfig { slot_size: 0, ..CivConfig::default() }),CivConfigError::SlotSize(0));
+        assert_eq!(read_bad(CivConfig { growth_factor: 1, ..CivConfig::default() }),CivConfigError::GrowthFactor(1));
+        assert_eq!(read_bad(CivConfig { tombs_limit: 1.5, ..CivConfig::default() }),CivConfigError::TombsLimit(1.5));
+        // the slot must have the size of the config
+        assert_eq!(read_bad(CivConfig { slot_size: 128, ..CivConfig::default() }),CivConfigError::SlotSize(64));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let cnt = 100_000u64;
+        let mut map: CivMap<u64,u64> = CivMap::with_capacity(cnt as usize);
+        let caps: Vec<usize> = map.data.iter().map(|ms| ms.keys.capacity()).collect();
+        assert!(map.data.iter().all(|ms| ms.keys.capacity() >= ms.capacity));
+        for i in 0 .. cnt {
+            map.insert(i * 7919 % cnt,i);
+        }
+        assert_eq!(map.len(),cnt as usize);
+        assert_eq!(caps,map.data.iter().map(|ms| ms.keys.capacity()).collect::<Vec<_>>());
+
+        map.reserve(cnt as usize);
+        assert!(map.data.iter().all(|ms| ms.keys.capacity() >= ms.capacity));
+        assert!(map.data.iter().map(|ms| ms.capacity).sum::<usize>() >= 2 * cnt as usize);
+        assert!((0 .. cnt).all(|i| map.get(&(i * 7919 % cnt)) == Some(&i)));
+    }
+
+    #[test]
+    fn test_compact() {
+        // removals alone never purge the levels with this limit
+        let mut map: CivMap<u64,u64> = CivMap::with_config(CivConfig::builder().tombs_limit(1.0).build().unwrap());
+        for i in 0 .. 100_000u64 {
+            map.insert(i,i);
+        }
+        for i in 0 .. 100_000u64 {
+            if i % 5 < 2 { map.remove(&i); }
+        }
+        let ctrl: BTreeMap<u64,u64> = (0 .. 100_000u64).filter(|i| i % 5 >= 2).map(|i|(i,i)).collect();
+        assert!(!map.compact_if(0.5));
+        assert!(map.compact_if(0.3));
+        assert!(!map.compact_if(0.0));
+        assert_eq!(map,ctrl);
+        assert_eq!(map.dead(),0);
+        assert_eq!(map.data.iter().filter(|ms| !ms.empty()).count(),1);
+        assert_eq!(map.data.iter().map(|ms| ms.keys.capacity()).sum::<usize>(),ctrl.len());
+        for i in 0 .. 1_000u64 {
+            assert_eq!(map.insert(i * 5,0),None);
+        }
+        assert_eq!(map.len(),ctrl.len() + 1_000);
+    }
+
     #[test]
     fn test_iter() {
         let cnt = 1_000_000;
@@ -782,4 +2306,62 @@ mod tests {
         assert_eq!(res,lib);
     }
     
+
+    #[test]
+    fn test_remove_purge() {
+        let cnt = 200_000u64;
+        let mut map: CivMap<u64,u64> = (0 .. cnt).map(|i|(i,i)).collect();
+        for i in 0 .. 100_000u64 {
+            map.insert(cnt + i,i);
+        }
+        let mut ctrl: BTreeMap<u64,u64> = map.iter_sorted().map(|(k,v)|(*k,*v)).collect();
+        for i in 0 .. cnt + 95_000 {
+            let k = i * 7919 % (cnt + 100_000);
+            match i % 3 {
+                0 => assert_eq!(map.remove_take(&k),ctrl.remove(&k)),
+                1 => assert_eq!(map.pop_first(),ctrl.pop_first()),
+                _ => assert_eq!(map.entry(k).or_insert(0),ctrl.entry(k).or_insert(0)),
+            }
+            if i % 1_000 == 0 {
+                map.check_invariants();
+            }
+        }
+        assert_eq!(map,ctrl);
+        assert!(map.tombs() < map.len());
+        assert_eq!(map.remove_range(1_000 .. 250_000),ctrl.range(1_000 .. 250_000).count());
+        ctrl.retain(|k,_| (*k < 1_000) || (*k >= 250_000));
+        map.retain(|k,_| k % 2 == 0);
+        ctrl.retain(|k,_| k % 2 == 0);
+        assert_eq!(map,ctrl);
+        assert!(map.tombs() <= 2 * map.len() + map.config().slot_size());
+    }
+
+    #[test]
+    fn test_policy() {
+        fn check<P: CompactionPolicy + 'static>(policy: P) -> usize {
+            let mut map: CivMap<u64,u64> = CivMap::new();
+            map.set_policy(policy);
+            let mut ctrl = BTreeMap::new();
+            let mut max_runs = 0;
+            for i in 0 .. 100_000u64 {
+                let k = i * 7919 % 60_000;
+                match i % 4 {
+                    3 => assert_eq!(map.remove_take(&k),ctrl.remove(&k)),
+                    _ => assert_eq!(map.insert(k,i),ctrl.insert(k,i)),
+                }
+                max_runs = max_runs.max(map.data.iter().filter(|ms| !ms.empty()).count());
+            }
+            map.check_invariants();
+            assert_eq!(map.len(),ctrl.len());
+            assert_eq!(map,ctrl);
+            let mut right = map.split_off(&30_000);
+            right.insert(100_000,0);
+            assert_eq!(right.len(),ctrl.range(30_000 ..).count() + 1);
+            max_runs
+        }
+        let binary = check(BinaryCounter);
+        // fewer runs cost more merges and the other way round
+        assert!(check(Leveled::new(4)) < binary);
+        assert!(check(Tiered::new(4)) > binary);
+    }
 }
@@ -1,6 +1,9 @@
 use serde::{Serialize,Deserialize,ser::{Serializer,SerializeStruct}};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::ops::{Bound,RangeBounds,RangeInclusive};
 
-use crate::Filled;
+use crate::{Flags,Filled,Comparator};
 
 pub(crate) mod set;
 pub(crate) mod map;
@@ -9,10 +12,204 @@ use set::SetMultiSlot;
 use map::MapMultiSlot;
 
 
+pub(crate) const SLOT_SIZE: usize = 64;
+pub(crate) const GROWTH_FACTOR: usize = 2;
 pub(crate) const TOMBS_LIMIT: f64 = 0.05;
 pub(crate) const AUTO_SHRINK_LIMIT: usize = 30_000_000;
 
 
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CivConfigError {
+    SlotSize(usize),
+    GrowthFactor(usize),
+    TombsLimit(f64),
+}
+
+// Per instance tuning, it is stored in the binary format along with the data
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub struct CivConfig {
+    slot_size: usize,
+    growth_factor: usize,
+    tombs_limit: f64,
+    shrink_limit: usize,
+}
+impl CivConfig {
+    pub fn builder() -> CivConfigBuilder {
+        CivConfigBuilder {
+            config: CivConfig::default(),
+        }
+    }
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+    pub fn growth_factor(&self) -> usize {
+        self.growth_factor
+    }
+    pub fn tombs_limit(&self) -> f64 {
+        self.tombs_limit
+    }
+    pub fn shrink_limit(&self) -> usize {
+        self.shrink_limit
+    }
+    // a config read back from the binary format goes through the same checks as the builder
+    pub(crate) fn validate(self) -> Result<CivConfig,CivConfigError> {
+        if self.slot_size == 0 { return Err(CivConfigError::SlotSize(self.slot_size)); }
+        // a level must fit the slot and all the levels below it
+        if self.growth_factor < 2 { return Err(CivConfigError::GrowthFactor(self.growth_factor)); }
+        if !((self.tombs_limit >= 0.0) && (self.tombs_limit <= 1.0)) { return Err(CivConfigError::TombsLimit(self.tombs_limit)); }
+        Ok(self)
+    }
+    fn with_slot_size(slot_size: usize) -> CivConfig {
+        CivConfig {
+            slot_size,
+            ..CivConfig::default()
+        }
+    }
+    // level n holds slot_sz * growth_factor^n entries
+    fn level_capacity(&self, slot_sz: usize, n: usize) -> usize {
+        self.growth_factor.saturating_pow(n as u32).saturating_mul(slot_sz)
+    }
+}
+impl Default for CivConfig {
+    fn default() -> CivConfig {
+        CivConfig {
+            slot_size: SLOT_SIZE,
+            growth_factor: GROWTH_FACTOR,
+            tombs_limit: TOMBS_LIMIT,
+            shrink_limit: AUTO_SHRINK_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct CivConfigBuilder {
+    config: CivConfig,
+}
+impl CivConfigBuilder {
+    pub fn slot_size(mut self, sz: usize) -> CivConfigBuilder {
+        self.config.slot_size = sz;
+        self
+    }
+    pub fn growth_factor(mut self, factor: usize) -> CivConfigBuilder {
+        self.config.growth_factor = factor;
+        self
+    }
+    pub fn tombs_limit(mut self, limit: f64) -> CivConfigBuilder {
+        // part of tombstones in a level which triggers its redistribution
+        self.config.tombs_limit = limit;
+        self
+    }
+    pub fn shrink_limit(mut self, limit: usize) -> CivConfigBuilder {
+        // emptied levels of at least this capacity give their memory back
+        self.config.shrink_limit = limit;
+        self
+    }
+    pub fn build(self) -> Result<CivConfig,CivConfigError> {
+        self.config.validate()
+    }
+}
+
+
+// State of a level as seen by a compaction policy
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct LevelInfo {
+    pub capacity: usize,
+    pub len: usize,  // live entries, 0 for an empty level
+}
+
+// Decides which levels the full slot is merged with, levels.len() stands for a new level on top.
+//   The slot and the levels of the range are merged into a single run, it goes to the lowest level
+//   from the start of the range on which fits it and is either in the range or empty
+pub trait CompactionPolicy: Send + Sync {
+    fn merge_range(&self, slot: usize, levels: &[LevelInfo]) -> RangeInclusive<usize>;
+}
+
+// The slot and all the levels below the first empty one are merged into it (default)
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct BinaryCounter;
+impl CompactionPolicy for BinaryCounter {
+    fn merge_range(&self, _slot: usize, levels: &[LevelInfo]) -> RangeInclusive<usize> {
+        let n = levels.iter().position(|l| l.len == 0).unwrap_or(levels.len());
+        0 ..= n
+    }
+}
+
+// Runs of a similar size are merged once there are ratio of them, as digits of a base ratio counter:
+//   fewer merges on insert, more runs to look through
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Tiered {
+    ratio: usize,
+}
+impl Tiered {
+    pub fn new(ratio: usize) -> Tiered {
+        Tiered { ratio }
+    }
+    pub fn ratio(&self) -> usize {
+        self.ratio
+    }
+}
+impl CompactionPolicy for Tiered {
+    fn merge_range(&self, slot: usize, levels: &[LevelInfo]) -> RangeInclusive<usize> {
+        let ratio = self.ratio.max(2);
+        // tier t holds runs of slot * ratio^t up to slot * ratio^(t+1) entries
+        let tier = |len: usize| {
+            let mut t = 0;
+            let mut bound = slot.max(1).saturating_mul(ratio);
+            while (len >= bound) && (bound < usize::MAX) {
+                t += 1;
+                bound = bound.saturating_mul(ratio);
+            }
+            t
+        };
+        let mut counts: Vec<usize> = Vec::new();
+        for l in levels.iter().filter(|l| l.len > 0) {
+            let t = tier(l.len);
+            if counts.len() <= t { counts.resize(t + 1,0); }
+            counts[t] += 1;
+        }
+        // the slot carries through the full tiers, their runs are merged with it
+        let carry = (0 ..).find(|&t| counts.get(t).copied().unwrap_or(0) + 1 < ratio).unwrap_or(0);
+        let merged = |l: &LevelInfo| (l.len > 0) && (tier(l.len) < carry);
+        match (levels.iter().position(merged),levels.iter().rposition(merged)) {
+            (Some(from),Some(to)) => from ..= to,
+            _ => {
+                let n = levels.iter().position(|l| l.len == 0).unwrap_or(levels.len());
+                n ..= n
+            },
+        }
+    }
+}
+
+// Runs are kept at the levels which are ratio times bigger than the previous such level, everything
+//   below the lowest one that fits is merged into it: few runs to look through, more merges on insert
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Leveled {
+    ratio: usize,
+}
+impl Leveled {
+    pub fn new(ratio: usize) -> Leveled {
+        Leveled { ratio }
+    }
+    pub fn ratio(&self) -> usize {
+        self.ratio
+    }
+}
+impl CompactionPolicy for Leveled {
+    fn merge_range(&self, slot: usize, levels: &[LevelInfo]) -> RangeInclusive<usize> {
+        let ratio = self.ratio.max(2);
+        let mut acc = slot;
+        let mut limit = slot.max(1);
+        for (n,l) in levels.iter().enumerate() {
+            acc += l.len;
+            if l.capacity < limit.saturating_mul(ratio) { continue; }
+            limit = l.capacity;
+            if acc <= limit { return 0 ..= n; }
+        }
+        0 ..= levels.len()
+    }
+}
+
+
 #[derive(Deserialize)]
 struct SerdeSlot<K,V> {
     size: usize,
@@ -64,11 +261,11 @@ impl<K,V> Slot<K,V> {
         self.data.capacity() * std::mem::size_of::<(K,V)>()
     }
 }
-impl<K: Ord,V> Slot<K,V> {
-    fn new() -> Slot<K,V> {
+impl<K,V> Slot<K,V> {
+    fn new(size: usize) -> Slot<K,V> {
         Slot {
-            size: 64,
-            data: Vec::with_capacity(64),
+            size,
+            data: Vec::with_capacity(size),
         }
     }
     #[cfg(test)]
@@ -78,28 +275,28 @@ impl<K: Ord,V> Slot<K,V> {
             data: Vec::with_capacity(s),
         }
     }
-    fn contains(&self, k: &K) -> Option<usize> { // Key slot idx 
+    fn contains<Q: ?Sized, C: Comparator<Q>>(&self, k: &Q, cmp: &C) -> Option<usize> where K: Borrow<Q> { // Key slot idx 
         for (i,(ki,_)) in self.data.iter().enumerate() {
-            if ki == k {
+            if cmp.compare(ki.borrow(),k) == Ordering::Equal {
                 return Some(i);
             }
         }
         None
     }
-    fn get(&self, k: &K) -> Option<&V> {
-        match self.contains(k) {
+    fn get<Q: ?Sized, C: Comparator<Q>>(&self, k: &Q, cmp: &C) -> Option<&V> where K: Borrow<Q> {
+        match self.contains(k,cmp) {
             Some(idx) => Some(&self.data[idx].1),
             None => None,
         }
     }
-    fn get_mut(&mut self, k: &K) -> Option<&mut V> {
-        match self.contains(k) {
+    fn get_mut<Q: ?Sized, C: Comparator<Q>>(&mut self, k: &Q, cmp: &C) -> Option<&mut V> where K: Borrow<Q> {
+        match self.contains(k,cmp) {
             Some(idx) => Some(&mut self.data[idx].1),
             None => None,
         }
     }
-    fn insert(&mut self, k: K, v: V) -> (Option<V>,Filled) {
-        let opt_v = match self.contains(&k) {
+    fn insert<C: Comparator<K>>(&mut self, k: K, v: V, cmp: &C) -> (Option<V>,Filled) {
+        let opt_v = match self.contains(&k,cmp) {
             Some(idx) => {
                 let mut tmp = v;
                 std::mem::swap(&mut tmp, &mut self.data[idx].1);
@@ -112,8 +309,11 @@ impl<K: Ord,V> Slot<K,V> {
         };
         (opt_v,if self.data.len() >= self.size { Filled::Full } else { Filled::HasSlots })
     }
-    fn remove(&mut self, k: &K) -> Option<V> {
-        match self.contains(&k) {
+    fn filled(&self) -> Filled {
+        if self.data.len() >= self.size { Filled::Full } else { Filled::HasSlots }
+    }
+    fn remove<Q: ?Sized, C: Comparator<Q>>(&mut self, k: &Q, cmp: &C) -> Option<V> where K: Borrow<Q> {
+        match self.contains(k,cmp) {
             Some(idx) => Some(self.data.swap_remove(idx).1),
             None => None,
         }
@@ -121,18 +321,18 @@ impl<K: Ord,V> Slot<K,V> {
     fn clear(&mut self) {
         self.data.clear();
     }
-    fn sorted_drain(&mut self) -> std::vec::Drain<(K,V)> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
+    fn sorted_drain<C: Comparator<K>>(&mut self, cmp: &C) -> std::vec::Drain<(K,V)> {
+        self.data.sort_by(|(k1,_),(k2,_)|cmp.compare(k1,k2));
         self.data.drain(..)
     }
-    fn into_map_multislot(&mut self) -> MapMultiSlot<K,V> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
+    fn into_map_multislot<C: Comparator<K>>(&mut self, cmp: &C) -> MapMultiSlot<K,V> {
+        self.data.sort_by(|(k1,_),(k2,_)|cmp.compare(k1,k2));
         let vc: Vec<(K,V)> = self.data.drain(..).collect();
         self.clear();
         MapMultiSlot::new(vc)
     }
-    fn into_set_multislot(&mut self) -> SetMultiSlot<K> {
-        self.data.sort_by(|(k1,_),(k2,_)|k1.cmp(k2));
+    fn into_set_multislot<C: Comparator<K>>(&mut self, cmp: &C) -> SetMultiSlot<K> {
+        self.data.sort_by(|(k1,_),(k2,_)|cmp.compare(k1,k2));
         let vc: Vec<K> = self.data.drain(..).map(|(k,_)|k).collect();
         self.clear();
         SetMultiSlot::new(vc)
@@ -140,6 +340,11 @@ impl<K: Ord,V> Slot<K,V> {
     fn iter(&self) -> std::slice::Iter<(K,V)> {
         self.data.iter()
     }
+    fn sorted_order<C: Comparator<K>>(&self, cmp: &C) -> Vec<usize> {
+        let mut order: Vec<usize> = (0 .. self.data.len()).collect();
+        order.sort_by(|i,j|cmp.compare(&self.data[*i].0,&self.data[*j].0));
+        order
+    }
 }
 
 
@@ -147,3 +352,306 @@ impl<K: Ord,V> Slot<K,V> {
 
 
         
+
+
+fn in_range<Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>>(k: &Q, range: &R, cmp: &C) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(s) => cmp.compare(k,s) != Ordering::Less,
+        Bound::Excluded(s) => cmp.compare(k,s) == Ordering::Greater,
+        Bound::Unbounded => true,
+    };
+    after_start && match range.end_bound() {
+        Bound::Included(e) => cmp.compare(k,e) != Ordering::Greater,
+        Bound::Excluded(e) => cmp.compare(k,e) == Ordering::Less,
+        Bound::Unbounded => true,
+    }
+}
+// span [from,to) of the sorted keys which are in range
+fn range_span<K: Borrow<Q>, Q: ?Sized, C: Comparator<Q>, R: RangeBounds<Q>>(keys: &[K], range: &R, cmp: &C) -> (usize,usize) {
+    let from = match range.start_bound() {
+        Bound::Included(s) => keys.partition_point(|k| cmp.compare(k.borrow(),s) == Ordering::Less),
+        Bound::Excluded(s) => keys.partition_point(|k| cmp.compare(k.borrow(),s) != Ordering::Greater),
+        Bound::Unbounded => 0,
+    };
+    let to = match range.end_bound() {
+        Bound::Included(e) => keys.partition_point(|k| cmp.compare(k.borrow(),e) != Ordering::Greater),
+        Bound::Excluded(e) => keys.partition_point(|k| cmp.compare(k.borrow(),e) == Ordering::Less),
+        Bound::Unbounded => keys.len(),
+    };
+    (from,to.max(from))
+}
+
+// Merge of two sorted runs, equal elements are combined with resolve(a,b)
+fn merge_sorted<T, O, R>(a: Vec<T>, b: Vec<T>, mut ord: O, mut resolve: R) -> Vec<T>
+where O: FnMut(&T,&T) -> Ordering,
+      R: FnMut(T,T) -> T
+{
+    if b.is_empty() { return a; }
+    if a.is_empty() { return b; }
+    let mut res = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+    while let (Some(x),Some(y)) = (a.peek(),b.peek()) {
+        match ord(x,y) {
+            Ordering::Less => res.extend(a.next()),
+            Ordering::Greater => res.extend(b.next()),
+            Ordering::Equal => if let (Some(x),Some(y)) = (a.next(),b.next()) {
+                res.push(resolve(x,y));
+            },
+        }
+    }
+    res.extend(a);
+    res.extend(b);
+    res
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub(crate) enum RunPos {
+    Slot(usize),        // index in Slot::data
+    Level(usize,usize), // level, index in level
+}
+
+// Lazy k-way merge over the slot (sorted by index) and all sorted levels,
+//   run 0 is the slot, run i+1 is the level i
+pub(crate) struct MergedRuns<'t,K,V,C> {
+    cmp: &'t C,
+    slot: &'t [(K,V)],
+    slot_order: Vec<usize>,
+    levels: Vec<(&'t [K],&'t Flags)>,
+    front: Vec<usize>,
+    back: Vec<usize>,
+}
+impl<'t,K,V,C: Comparator<K>> MergedRuns<'t,K,V,C> {
+    fn new(slot: &'t Slot<K,V>, levels: Vec<(&'t [K],&'t Flags)>, cmp: &'t C) -> MergedRuns<'t,K,V,C> {
+        let slot_order = slot.sorted_order(cmp);
+        let mut front = Vec::with_capacity(levels.len() + 1);
+        let mut back = Vec::with_capacity(levels.len() + 1);
+        front.push(0);
+        back.push(slot_order.len());
+        for (keys,_) in &levels {
+            front.push(0);
+            back.push(keys.len());
+        }
+        MergedRuns {
+            cmp,
+            slot: &slot.data,
+            slot_order,
+            levels,
+            front,
+            back,
+        }
+    }
+    fn restrict<Q: ?Sized, R: RangeBounds<Q>>(&mut self, range: &R) where K: Borrow<Q>, C: Comparator<Q> {
+        let cmp = self.cmp;
+        for run in 0 .. self.front.len() {
+            self.front[run] = match range.start_bound() {
+                Bound::Included(s) => self.partition(run,|k| cmp.compare(k.borrow(),s) == Ordering::Less),
+                Bound::Excluded(s) => self.partition(run,|k| cmp.compare(k.borrow(),s) != Ordering::Greater),
+                Bound::Unbounded => 0,
+            };
+            self.back[run] = match range.end_bound() {
+                Bound::Included(e) => self.partition(run,|k| cmp.compare(k.borrow(),e) != Ordering::Greater),
+                Bound::Excluded(e) => self.partition(run,|k| cmp.compare(k.borrow(),e) == Ordering::Less),
+                Bound::Unbounded => self.run_len(run),
+            };
+            if self.back[run] < self.front[run] {
+                self.back[run] = self.front[run];
+            }
+        }
+    }
+    fn run_len(&self, run: usize) -> usize {
+        match run {
+            0 => self.slot_order.len(),
+            _ => self.levels[run-1].0.len(),
+        }
+    }
+    // number of leading run entries satisfying pred (pred must be monotone)
+    fn partition<F: Fn(&K) -> bool>(&self, run: usize, pred: F) -> usize {
+        let mut lo = 0;
+        let mut hi = self.run_len(run);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match pred(self.key(run,mid)) {
+                true => lo = mid + 1,
+                false => hi = mid,
+            }
+        }
+        lo
+    }
+    // live entries of the run before idx
+    fn live_before(&self, run: usize, idx: usize) -> usize {
+        match run {
+            0 => idx,
+            _ => self.levels[run-1].1.rank(idx),
+        }
+    }
+    fn live_select(&self, run: usize, n: usize) -> Option<usize> {
+        match run {
+            0 => match n < self.slot_order.len() {
+                true => Some(n),
+                false => None,
+            },
+            _ => self.levels[run-1].1.select(n).filter(|idx| *idx < self.levels[run-1].0.len()),
+        }
+    }
+    fn rank<Q: ?Sized>(&self, k: &Q) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        (0 .. self.front.len()).fold(0,|acc,run| acc + self.live_before(run,self.lower_bound(run,k)))
+    }
+    // number of run entries < k
+    fn lower_bound<Q: ?Sized>(&self, run: usize, k: &Q) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        self.partition(run,|x| self.cmp.compare(x.borrow(),k) == Ordering::Less)
+    }
+    fn select(&self, n: usize) -> Option<(&'t K,RunPos)> {
+        // the n-th key is in one of the runs: binary search by live rank in every run
+        for run in 0 .. self.front.len() {
+            let mut lo = 0;
+            let mut hi = self.live_before(run,self.run_len(run));
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                let idx = self.live_select(run,mid)?;
+                let k = self.key(run,idx);
+                let r = self.rank(k);
+                match r.cmp(&n) {
+                    Ordering::Equal => return Some((k,self.pos(run,idx))),
+                    Ordering::Less => lo = mid + 1,
+                    Ordering::Greater => hi = mid,
+                }
+            }
+        }
+        None
+    }
+    fn key(&self, run: usize, idx: usize) -> &'t K {
+        match run {
+            0 => &self.slot[self.slot_order[idx]].0,
+            _ => &self.levels[run-1].0[idx],
+        }
+    }
+    fn live(&self, run: usize, idx: usize) -> bool {
+        match run {
+            0 => true,
+            _ => self.levels[run-1].1.get(idx),
+        }
+    }
+    fn pos(&self, run: usize, idx: usize) -> RunPos {
+        match run {
+            0 => RunPos::Slot(self.slot_order[idx]),
+            _ => RunPos::Level(run-1,idx),
+        }
+    }
+    // first live index of the run at or after idx (run_len if none)
+    fn live_from(&self, run: usize, idx: usize) -> usize {
+        match (run,self.live(run,idx)) {
+            (0,_) | (_,true) => idx,
+            // a run of tombstones is jumped over with the rank tree
+            _ => {
+                let flags = self.levels[run-1].1;
+                flags.select(flags.rank(idx)).unwrap_or(self.run_len(run)).min(self.run_len(run))
+            },
+        }
+    }
+    // one past the last live index of the run before idx (0 if none)
+    fn live_until(&self, run: usize, idx: usize) -> usize {
+        match (run,self.live(run,idx-1)) {
+            (0,_) | (_,true) => idx,
+            _ => {
+                let flags = self.levels[run-1].1;
+                match flags.rank(idx) {
+                    0 => 0,
+                    r => flags.select(r-1).map_or(0,|i| i+1),
+                }
+            },
+        }
+    }
+    fn skip_front(&mut self, run: usize) {
+        if self.front[run] < self.back[run] {
+            self.front[run] = self.live_from(run,self.front[run]).min(self.back[run]);
+        }
+    }
+    fn skip_back(&mut self, run: usize) {
+        if self.front[run] < self.back[run] {
+            self.back[run] = self.live_until(run,self.back[run]).max(self.front[run]);
+        }
+    }
+    fn peek_front(&mut self) -> Option<(usize,&'t K)> {
+        let mut best: Option<(usize,&'t K)> = None;
+        for run in 0 .. self.front.len() {
+            self.skip_front(run);
+            if self.front[run] < self.back[run] {
+                let k = self.key(run,self.front[run]);
+                match best {
+                    Some((_,bk)) if self.cmp.compare(bk,k) != Ordering::Greater => {},
+                    _ => best = Some((run,k)),
+                }
+            }
+        }
+        best
+    }
+    fn next(&mut self) -> Option<(&'t K,RunPos)> {
+        self.peek_front().map(|(run,k)| {
+            let idx = self.front[run];
+            self.front[run] += 1;
+            (k,self.pos(run,idx))
+        })
+    }
+    fn next_back(&mut self) -> Option<(&'t K,RunPos)> {
+        let mut best: Option<(usize,&'t K)> = None;
+        for run in 0 .. self.back.len() {
+            self.skip_back(run);
+            if self.front[run] < self.back[run] {
+                let k = self.key(run,self.back[run]-1);
+                match best {
+                    Some((_,bk)) if self.cmp.compare(bk,k) != Ordering::Less => {},
+                    _ => best = Some((run,k)),
+                }
+            }
+        }
+        best.map(|(run,k)| {
+            self.back[run] -= 1;
+            (k,self.pos(run,self.back[run]))
+        })
+    }
+
+    // cursor: `front` is a gap between the keys of every run,
+    //   `back` is kept at the end of every run
+    fn seek<Q: ?Sized>(&mut self, k: &Q) where K: Borrow<Q>, C: Comparator<Q> {
+        for run in 0 .. self.front.len() {
+            self.front[run] = self.lower_bound(run,k);
+        }
+    }
+    fn seek_first(&mut self) {
+        for run in 0 .. self.front.len() {
+            self.front[run] = 0;
+        }
+    }
+    fn seek_last(&mut self) {
+        for run in 0 .. self.front.len() {
+            self.front[run] = self.back[run];
+        }
+    }
+    fn peek_prev(&self) -> Option<(usize,usize,&'t K)> {
+        let mut best: Option<(usize,usize,&'t K)> = None;
+        for run in 0 .. self.front.len() {
+            let idx = match self.front[run] {
+                0 => 0,
+                front => self.live_until(run,front),
+            };
+            if idx > 0 {
+                let k = self.key(run,idx-1);
+                match best {
+                    Some((_,_,bk)) if self.cmp.compare(bk,k) != Ordering::Less => {},
+                    _ => best = Some((run,idx-1,k)),
+                }
+            }
+        }
+        best
+    }
+    fn peek_next(&mut self) -> Option<(&'t K,RunPos)> {
+        self.peek_front().map(|(run,k)| (k,self.pos(run,self.front[run])))
+    }
+    fn prev(&mut self) -> Option<(&'t K,RunPos)> {
+        self.peek_prev().map(|(run,idx,k)| {
+            self.front[run] = idx;
+            (k,self.pos(run,idx))
+        })
+    }
+}
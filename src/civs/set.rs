@@ -5,10 +5,17 @@ use serde::{
 };
 use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
 use std::io::{Read,Write};
+use std::borrow::Borrow;
+use std::ops::{Bound,Range,RangeBounds};
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::hash::{Hash,Hasher,BuildHasher};
+use std::collections::{BTreeSet,HashSet};
+use std::sync::Arc;
 
 use crate::{
-    Flags,Filled,Binary,
-    civs::{Slot,TOMBS_LIMIT,AUTO_SHRINK_LIMIT},
+    Flags,Filled,Binary,Comparator,NaturalOrder,
+    civs::{Slot,MergedRuns,RunPos,CivConfig,CivConfigError,CompactionPolicy,LevelInfo,BinaryCounter,merge_sorted,in_range,range_span},
 };
 
 #[derive(Deserialize)]
@@ -27,7 +34,7 @@ impl<K> std::convert::TryFrom<SerdeSetMultiSlot<K>> for SetMultiSlot<K> {
         }
         Ok(SetMultiSlot {
             capacity: slot.capacity,
-            flags: Flags(slot.flags),
+            flags: Flags::from_bits(slot.flags),
             data: slot.data,
         })
     }
@@ -58,10 +65,27 @@ impl<K> SetMultiSlot<K> {
     fn heap_mem(&self) -> usize {
         self.flags.heap_mem() + self.data.capacity() * std::mem::size_of::<K>()
     }
+    fn filtered_iter(&self) -> SetMultiSlotFilterIterator<'_,K> {
+        SetMultiSlotFilterIterator {
+            iter: self.data.iter().enumerate(),
+            flags: &self.flags,
+        }
+    }
+    fn filtered_drain(&mut self) -> SetMultiSlotFilterDrainIterator<'_,K> {
+        SetMultiSlotFilterDrainIterator {
+            iter: self.data.drain(..).enumerate(),
+            flags: &mut self.flags,
+        }
+    }
+    fn into_filtered_iter(self) -> SetMultiSlotIntoIterator<K> {
+        SetMultiSlotIntoIterator {
+            iter: self.data.into_iter().enumerate(),
+            flags: self.flags,
+        }
+    }
 }
-impl<K: Ord> SetMultiSlot<K> {
-    fn new_empty(sz: usize, slot_sz: usize) -> SetMultiSlot<K> {
-        let cap = slot_sz * (0x1 << (sz-1));
+impl<K> SetMultiSlot<K> {
+    fn new_empty(cap: usize) -> SetMultiSlot<K> {
         SetMultiSlot {
             capacity: cap,
             flags: Flags::nulls(cap),
@@ -75,15 +99,23 @@ impl<K: Ord> SetMultiSlot<K> {
             data: data,
         }
     }
+    fn new_unallocated(cap: usize) -> SetMultiSlot<K> {
+        // empty level, memory is reserved on the first merge into it
+        SetMultiSlot {
+            capacity: cap,
+            flags: Flags::tmp(),
+            data: Vec::new(),
+        }
+    }
     fn empty(&self) -> bool {
         self.data.len() == 0
     }
     fn check_len(&self) -> usize {
         self.flags.0.iter().fold(0,|acc,x| acc + x.count_ones() as usize)
     }
-    fn contains(&self, k: &K) -> Option<usize> {
-        if (self.data.len() == 0)||(*k < self.data[0])||(*k > self.data[self.data.len()-1]) { return None; }
-        match self.data.binary_search(k) {
+    fn contains<Q: ?Sized, C: Comparator<Q>>(&self, k: &Q, cmp: &C) -> Option<usize> where K: Borrow<Q> {
+        if (self.data.len() == 0)||(cmp.compare(k,self.data[0].borrow()) == Ordering::Less)||(cmp.compare(k,self.data[self.data.len()-1].borrow()) == Ordering::Greater) { return None; }
+        match self.data.binary_search_by(|x| cmp.compare(x.borrow(),k)) {
             Ok(idx) => match self.flags.get(idx) {
                 true => Some(idx),
                 false => None,
@@ -95,52 +127,428 @@ impl<K: Ord> SetMultiSlot<K> {
         self.flags.set_nulls();
         self.data.clear();
     }
+    fn split_off(&mut self, at: usize) -> SetMultiSlot<K> {
+        // the right part keeps the capacity of the level
+        let len = self.data.len();
+        SetMultiSlot {
+            capacity: self.capacity,
+            flags: self.flags.split_off(at,len),
+            data: self.data.split_off(at),
+        }
+    }
     fn shrink_to_fit(&mut self) {
         self.data.shrink_to_fit();
     }
 }
 
+struct SetMultiSlotFilterIterator<'t,K> {
+    iter: std::iter::Enumerate<std::slice::Iter<'t,K>>,
+    flags: &'t Flags,
+}
+impl<'t,K> Iterator for SetMultiSlotFilterIterator<'t,K> {
+    type Item = &'t K;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next() {
+                Some((n,k)) if self.flags.get(n) => break Some(k),
+                Some(_) => continue,
+                None => break None,
+            }
+        }
+    }
+}
 
-const CURRENT_CIVS_SET_VERSION: (u32,u32) = (0,1);
+struct SetMultiSlotFilterDrainIterator<'t,K> {
+    iter: std::iter::Enumerate<std::vec::Drain<'t,K>>,
+    flags: &'t mut Flags,
+}
+impl<'t,K> Iterator for SetMultiSlotFilterDrainIterator<'t,K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next() {
+                Some((n,k)) if self.flags.get(n) => break Some(k),
+                Some(_) => continue,
+                None => break None,
+            }
+        }
+    }
+}
+impl<'t,K> Drop for SetMultiSlotFilterDrainIterator<'t,K> {
+    fn drop(&mut self) {
+        self.flags.set_nulls();
+    }
+}
+
+struct SetMultiSlotIntoIterator<K> {
+    iter: std::iter::Enumerate<std::vec::IntoIter<K>>,
+    flags: Flags,
+}
+impl<K> Iterator for SetMultiSlotIntoIterator<K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next() {
+                Some((n,k)) if self.flags.get(n) => break Some(k),
+                Some(_) => continue,
+                None => break None,
+            }
+        }
+    }
+}
+
+
+pub struct SetIter<'t,K> {
+    slot_iter: Option<std::slice::Iter<'t,(K,())>>,
+    cur_data_iter: Option<SetMultiSlotFilterIterator<'t,K>>,
+    data_iter: std::slice::Iter<'t,SetMultiSlot<K>>,
+}
+impl<'t,K> Iterator for SetIter<'t,K> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(iter) = &mut self.slot_iter {
+            match iter.next() {
+                Some((k,_)) => return Some(k),
+                None => { self.slot_iter = None; },
+            }
+        }
+        loop {
+            if let Some(iter) = &mut self.cur_data_iter {
+                if let Some(k) = iter.next() {
+                    return Some(k);
+                }
+            }
+            match self.data_iter.next() {
+                Some(ms) => { self.cur_data_iter = Some(ms.filtered_iter()); },
+                None => { self.cur_data_iter = None; break None; },
+            }
+        }
+    }
+}
+
+pub struct SetSortedIter<'t,K,C = NaturalOrder> {
+    runs: MergedRuns<'t,K,(),C>,
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetSortedIter<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runs.next().map(|(k,_)| k)
+    }
+}
+impl<'t,K,C: Comparator<K>> DoubleEndedIterator for SetSortedIter<'t,K,C> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.runs.next_back().map(|(k,_)| k)
+    }
+}
+
+pub struct SetCursor<'t,K,C = NaturalOrder> {
+    runs: MergedRuns<'t,K,(),C>,
+}
+impl<'t,K,C: Comparator<K>> SetCursor<'t,K,C> {
+    pub fn seek<Q: ?Sized>(&mut self, k: &Q) where K: Borrow<Q>, C: Comparator<Q> {
+        // before the first key >= k
+        self.runs.seek(k);
+    }
+    pub fn seek_first(&mut self) {
+        self.runs.seek_first();
+    }
+    pub fn seek_last(&mut self) {
+        self.runs.seek_last();
+    }
+    pub fn peek_next(&mut self) -> Option<&'t K> {
+        self.runs.peek_next().map(|(k,_)| k)
+    }
+    pub fn peek_prev(&self) -> Option<&'t K> {
+        self.runs.peek_prev().map(|(_,_,k)| k)
+    }
+    pub fn prev(&mut self) -> Option<&'t K> {
+        self.runs.prev().map(|(k,_)| k)
+    }
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetCursor<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runs.next().map(|(k,_)| k)
+    }
+}
+
+pub struct SetUnion<'t,K,C: Comparator<K> = NaturalOrder> {
+    a: Peekable<SetSortedIter<'t,K,C>>,
+    b: Peekable<SetSortedIter<'t,K,C>>,
+    cmp: &'t C,
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetUnion<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ord = match (self.a.peek(),self.b.peek()) {
+            (Some(x),Some(y)) => self.cmp.compare(x,y),
+            (Some(_),None) => Ordering::Less,
+            (None,Some(_)) => Ordering::Greater,
+            (None,None) => return None,
+        };
+        match ord {
+            Ordering::Less => self.a.next(),
+            Ordering::Greater => self.b.next(),
+            Ordering::Equal => {
+                self.b.next();
+                self.a.next()
+            },
+        }
+    }
+}
+
+pub struct SetIntersection<'t,K,C: Comparator<K> = NaturalOrder> {
+    a: Peekable<SetSortedIter<'t,K,C>>,
+    b: Peekable<SetSortedIter<'t,K,C>>,
+    cmp: &'t C,
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetIntersection<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ord = match (self.a.peek(),self.b.peek()) {
+                (Some(x),Some(y)) => self.cmp.compare(x,y),
+                _ => return None,
+            };
+            match ord {
+                Ordering::Less => { self.a.next(); },
+                Ordering::Greater => { self.b.next(); },
+                Ordering::Equal => {
+                    self.b.next();
+                    return self.a.next();
+                },
+            }
+        }
+    }
+}
+
+pub struct SetDifference<'t,K,C: Comparator<K> = NaturalOrder> {
+    a: Peekable<SetSortedIter<'t,K,C>>,
+    b: Peekable<SetSortedIter<'t,K,C>>,
+    cmp: &'t C,
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetDifference<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ord = match (self.a.peek(),self.b.peek()) {
+                (Some(x),Some(y)) => self.cmp.compare(x,y),
+                (Some(_),None) => Ordering::Less,
+                (None,_) => return None,
+            };
+            match ord {
+                Ordering::Less => return self.a.next(),
+                Ordering::Greater => { self.b.next(); },
+                Ordering::Equal => {
+                    self.a.next();
+                    self.b.next();
+                },
+            }
+        }
+    }
+}
+
+pub struct SetSymmetricDifference<'t,K,C: Comparator<K> = NaturalOrder> {
+    a: Peekable<SetSortedIter<'t,K,C>>,
+    b: Peekable<SetSortedIter<'t,K,C>>,
+    cmp: &'t C,
+}
+impl<'t,K,C: Comparator<K>> Iterator for SetSymmetricDifference<'t,K,C> {
+    type Item = &'t K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ord = match (self.a.peek(),self.b.peek()) {
+                (Some(x),Some(y)) => self.cmp.compare(x,y),
+                (Some(_),None) => Ordering::Less,
+                (None,Some(_)) => Ordering::Greater,
+                (None,None) => return None,
+            };
+            match ord {
+                Ordering::Less => return self.a.next(),
+                Ordering::Greater => return self.b.next(),
+                Ordering::Equal => {
+                    self.a.next();
+                    self.b.next();
+                },
+            }
+        }
+    }
+}
+
+pub struct SetIntoIter<K> {
+    slot_iter: std::vec::IntoIter<(K,())>,
+    cur_data_iter: Option<SetMultiSlotIntoIterator<K>>,
+    data_iter: std::vec::IntoIter<SetMultiSlot<K>>,
+}
+impl<K> Iterator for SetIntoIter<K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((k,_)) = self.slot_iter.next() {
+            return Some(k);
+        }
+        loop {
+            if let Some(iter) = &mut self.cur_data_iter {
+                if let Some(k) = iter.next() {
+                    return Some(k);
+                }
+            }
+            match self.data_iter.next() {
+                Some(ms) => { self.cur_data_iter = Some(ms.into_filtered_iter()); },
+                None => { self.cur_data_iter = None; break None; },
+            }
+        }
+    }
+}
+
+pub struct SetDrain<'t,K> {
+    slot_iter: std::vec::Drain<'t,(K,())>,
+    cur_data_iter: Option<SetMultiSlotFilterDrainIterator<'t,K>>,
+    data_iter: std::slice::IterMut<'t,SetMultiSlot<K>>,
+}
+impl<'t,K> Iterator for SetDrain<'t,K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((k,_)) = self.slot_iter.next() {
+            return Some(k);
+        }
+        loop {
+            if let Some(iter) = &mut self.cur_data_iter {
+                if let Some(k) = iter.next() {
+                    return Some(k);
+                }
+            }
+            match self.data_iter.next() {
+                Some(ms) => { self.cur_data_iter = Some(ms.filtered_drain()); },
+                None => { self.cur_data_iter = None; break None; },
+            }
+        }
+    }
+}
+impl<'t,K> Drop for SetDrain<'t,K> {
+    fn drop(&mut self) {
+        // levels which were not reached by the iterator still have to be emptied
+        for ms in &mut self.data_iter {
+            ms.flags.set_nulls();
+            ms.data.clear();
+        }
+    }
+}
+
+pub struct SetExtractIf<'t,K,C: Comparator<K>,F> {
+    set: &'t mut CivSet<K,C>,
+    pred: F,
+    slot_idx: usize,
+    msi: usize,
+    idx: usize,
+}
+impl<'t,K: Clone,C: Comparator<K>,F: FnMut(&K) -> bool> Iterator for SetExtractIf<'t,K,C,F> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let set = &mut *self.set;
+        while self.slot_idx < set.slot.data.len() {
+            if (self.pred)(&set.slot.data[self.slot_idx].0) {
+                set.len -= 1;
+                return Some(set.slot.data.swap_remove(self.slot_idx).0);
+            }
+            self.slot_idx += 1;
+        }
+        while self.msi < set.data.len() {
+            let ms = &mut set.data[self.msi];
+            while self.idx < ms.data.len() {
+                let idx = self.idx;
+                self.idx += 1;
+                if ms.flags.get(idx) && (self.pred)(&ms.data[idx]) {
+                    ms.flags.unset(idx);
+                    set.len -= 1;
+                    set.tombs += 1;
+                    return Some(ms.data[idx].clone());
+                }
+            }
+            self.msi += 1;
+            self.idx = 0;
+        }
+        None
+    }
+}
+impl<'t,K,C: Comparator<K>,F> Drop for SetExtractIf<'t,K,C,F> {
+    fn drop(&mut self) {
+        // the tombstones left by the iterator are reclaimed at once
+        self.set.check_levels();
+    }
+}
+
+
+
+const CURRENT_CIVS_SET_VERSION: (u32,u32) = (0,2);
+
+#[derive(Debug)]
+pub enum CivSetBuildError {
+    Unsorted(usize),
+    Duplicate(usize),
+}
 
 #[derive(Debug)]
 pub enum CivSetIoError {
     WriteHeader,
+    WriteConfig(bincode::Error),
     WriteSlot(bincode::Error),
     WriteData(bincode::Error),
     ReadHeader,
+    ReadConfig(bincode::Error),
     ReadSlot(bincode::Error),
     ReadData(bincode::Error),
     InvalidHeader,
     InvalidVersion(u32,u32),
+    InvalidConfig(CivConfigError),
 }
 
-impl<K: Ord + Serialize + DeserializeOwned> Binary for CivSet<K> {
+impl<K: Serialize + DeserializeOwned, C: Comparator<K> + Default> Binary for CivSet<K,C> {
     type IoError = CivSetIoError;
     fn memory(&self) -> usize {
         let mut data_mem = self.data.capacity() * std::mem::size_of::<SetMultiSlot<K>>();
         for ms in &self.data {
             data_mem += ms.heap_mem();
         }
-        std::mem::size_of::<CivSet<K>>() + self.slot.heap_mem() + data_mem
+        std::mem::size_of::<CivSet<K,C>>() + self.slot.heap_mem() + data_mem
     }
     fn into_writer<W: Write>(&self, mut wrt: W) -> Result<(),Self::IoError> {
         let version = CURRENT_CIVS_SET_VERSION;
         write!(wrt,"CIVS").map_err(|_|CivSetIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.0).map_err(|_|CivSetIoError::WriteHeader)?;
         wrt.write_u32::<LittleEndian>(version.1).map_err(|_|CivSetIoError::WriteHeader)?;
+        bincode::serialize_into(&mut wrt,&self.config).map_err(CivSetIoError::WriteConfig)?;
         bincode::serialize_into(&mut wrt,&self.slot).map_err(CivSetIoError::WriteSlot)?;
         bincode::serialize_into(&mut wrt,&self.data).map_err(CivSetIoError::WriteData)
     }
-    fn from_reader<R: Read>(mut rdr: R) -> Result<CivSet<K>,Self::IoError> {
+    fn from_reader<R: Read>(mut rdr: R) -> Result<CivSet<K,C>,Self::IoError> {
         let mut buf = [0; 4];
         rdr.read_exact(&mut buf).map_err(|_|CivSetIoError::ReadHeader)?;
         if buf != "CIVS".as_bytes()[0..4] { return Err(CivSetIoError::InvalidHeader); }
         let maj = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
         let min = rdr.read_u32::<LittleEndian>().map_err(|_|CivSetIoError::ReadHeader)?;
-        if (maj != 0)||(min != 1) { return Err(CivSetIoError::InvalidVersion(maj,min)); }
+        if (maj != 0)||((min != 1)&&(min != 2)) { return Err(CivSetIoError::InvalidVersion(maj,min)); }
+        // version 0.1 has no config, only the slot size is known
+        let config: Option<CivConfig> = match min {
+            1 => None,
+            _ => Some(bincode::deserialize_from(&mut rdr).map_err(CivSetIoError::ReadConfig)?),
+        };
         let slot: Slot<K,()> = bincode::deserialize_from(&mut rdr).map_err(CivSetIoError::ReadSlot)?;
+        let config = config.unwrap_or_else(|| CivConfig::with_slot_size(slot.max_size()));
+        let config = config.validate().map_err(CivSetIoError::InvalidConfig)?;
+        if slot.max_size() != config.slot_size() { return Err(CivSetIoError::InvalidConfig(CivConfigError::SlotSize(slot.max_size()))); }
         let data: Vec<SetMultiSlot<K>> = bincode::deserialize_from(&mut rdr).map_err(CivSetIoError::ReadData)?;
         let mut len = slot.len();
         let mut tombs = 0;
@@ -154,6 +562,10 @@ impl<K: Ord + Serialize + DeserializeOwned> Binary for CivSet<K> {
             tombs: tombs,
             slot: slot,
             data: data,
+            cmp: C::default(),
+            config,
+            // like the comparator, the policy is not stored
+            policy: Arc::new(BinaryCounter),
             
             tmp_merge_vec: Vec::new(),
             tmp_merge_flags: Flags::tmp(),
@@ -162,16 +574,19 @@ impl<K: Ord + Serialize + DeserializeOwned> Binary for CivSet<K> {
 }
 
 #[derive(Clone)]
-pub struct CivSet<K> {
+pub struct CivSet<K,C = NaturalOrder> {
     len: usize,
     tombs: usize,
     slot: Slot<K,()>,
     data: Vec<SetMultiSlot<K>>,
+    cmp: C,
+    config: CivConfig,
+    policy: Arc<dyn CompactionPolicy>,
 
     tmp_merge_vec: Vec<K>,
     tmp_merge_flags: Flags,
 }
-impl<K: std::fmt::Debug> std::fmt::Debug for CivSet<K> {
+impl<K: std::fmt::Debug, C> std::fmt::Debug for CivSet<K,C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CivSet")
             .field("len", &self.len)
@@ -183,16 +598,333 @@ impl<K: std::fmt::Debug> std::fmt::Debug for CivSet<K> {
 }
 impl<K: Ord> CivSet<K> {
     pub fn new() -> CivSet<K> {
+        CivSet::with_comparator(NaturalOrder)
+    }
+    pub fn with_capacity(n: usize) -> CivSet<K> {
+        let mut set = CivSet::new();
+        set.reserve(n);
+        set
+    }
+    pub fn with_config(config: CivConfig) -> CivSet<K> {
+        CivSet::with_config_and_comparator(config,NaturalOrder)
+    }
+    pub fn from_sorted_iter<I: IntoIterator<Item = K>>(iter: I) -> Result<CivSet<K>,CivSetBuildError> {
+        let iter = iter.into_iter();
+        let mut data: Vec<K> = Vec::with_capacity(iter.size_hint().0);
+        for (i,k) in iter.enumerate() {
+            if let Some(last) = data.last() {
+                match last.cmp(&k) {
+                    Ordering::Less => {},
+                    Ordering::Equal => return Err(CivSetBuildError::Duplicate(i)),
+                    Ordering::Greater => return Err(CivSetBuildError::Unsorted(i)),
+                }
+            }
+            data.push(k);
+        }
+        let mut set = CivSet::new();
+        set.load_sorted(data);
+        Ok(set)
+    }
+    pub fn from_sorted_iter_unchecked<I: IntoIterator<Item = K>>(iter: I) -> CivSet<K> {
+        // iter must be strictly increasing; unsorted or repeated keys are a logic error, not UB:
+        //   lookups may miss keys and iteration order is unspecified, debug builds panic on them
+        let data: Vec<K> = iter.into_iter().collect();
+        debug_assert!(data.windows(2).all(|w| w[0] < w[1]),"from_sorted_iter_unchecked: keys are not strictly increasing");
+        let mut set = CivSet::new();
+        set.load_sorted(data);
+        set
+    }
+    pub fn from_unsorted_vec(data: Vec<K>) -> CivSet<K> {
+        let mut set = CivSet::new();
+        set.load_unsorted(data);
+        set
+    }
+}
+impl<K, C: Comparator<K>> CivSet<K,C> {
+    pub fn with_comparator(cmp: C) -> CivSet<K,C> {
+        CivSet::with_config_and_comparator(CivConfig::default(),cmp)
+    }
+    pub fn with_config_and_comparator(config: CivConfig, cmp: C) -> CivSet<K,C> {
         CivSet {
             len: 0,
             tombs: 0,
-            slot: Slot::new(),
+            slot: Slot::new(config.slot_size()),
             data: Vec::new(),
+            cmp,
+            config,
+            policy: Arc::new(BinaryCounter),
 
             tmp_merge_vec: Vec::new(),
             tmp_merge_flags: Flags::tmp(),
         }
     }
+
+    pub fn iter(&self) -> SetIter<'_,K> {
+        SetIter {
+            slot_iter: Some(self.slot.iter()),
+            cur_data_iter: None,
+            data_iter: self.data.iter(),
+        }
+    }
+    fn load_unsorted(&mut self, data: Vec<K>) {
+        let data = self.sort_unique(data);
+        self.load_sorted(data);
+    }
+    fn sort_unique(&self, mut data: Vec<K>) -> Vec<K> {
+        // the first of equal keys is kept
+        let cmp = &self.cmp;
+        data.sort_by(|a,b|cmp.compare(a,b));
+        data.dedup_by(|next,prev|cmp.compare(next,prev) == Ordering::Equal);
+        data
+    }
+    fn take_sorted(&mut self) -> Vec<K> {
+        // sorted keys, the set is cleared;
+        //   levels grow geometrically, so merging them from the smallest up is linear
+        let cmp = &self.cmp;
+        let mut data: Vec<K> = self.slot.sorted_drain(cmp).map(|(k,_)|k).collect();
+        for ms in &mut self.data {
+            let run = ms.filtered_drain().collect();
+            data = merge_sorted(data,run,|a,b|cmp.compare(a,b),|a,_|a);
+        }
+        self.clear();
+        data
+    }
+    pub fn absorb(&mut self, mut other: CivSet<K,C>) {
+        // keys already present are kept, as with insert
+        let run = other.take_sorted();
+        self.merge_run(run);
+    }
+    fn merge_run(&mut self, mut run: Vec<K>) {
+        // a sorted run of distinct keys goes with the slot and the lowest levels into the first level
+        //   that fits them all, the levels above it are not rewritten; keys already present are kept
+        if run.is_empty() { return; }
+        let mut cnt = self.slot.len() + run.len();
+        let mut t = 0;
+        loop {
+            if t == self.data.len() {
+                self.data.push(SetMultiSlot::new_unallocated(self.level_capacity(t)));
+            }
+            let ms = &self.data[t];
+            cnt += ms.flags.rank(ms.data.len());
+            if ms.capacity >= cnt { break; }
+            t += 1;
+        }
+        let cmp = &self.cmp;
+        let upper = &self.data[t+1 ..];
+        run.retain(|k| upper.iter().all(|ms| ms.contains(k,cmp).is_none()));
+        let mut data: Vec<K> = self.slot.sorted_drain(cmp).map(|(k,_)|k).collect();
+        self.slot.clear();
+        for ms in &mut self.data[..= t] {
+            let level = ms.filtered_drain().collect();
+            data = merge_sorted(data,level,|a,b|cmp.compare(a,b),|a,_|a);
+            ms.clear();
+        }
+        let data = merge_sorted(data,run,|a,b|cmp.compare(a,b),|a,_|a);
+        let ms = &mut self.data[t];
+        let c = data.len();
+        ms.data.extend(data);
+        if c > 0 {
+            ms.flags.set_ones(c);
+        }
+        self.recount();
+    }
+    pub fn split_off<Q: ?Sized>(&mut self, k: &Q) -> CivSet<K,C> where K: Borrow<Q>, C: Comparator<Q> + Clone {
+        // keys >= k are moved out, every level is split in place
+        //   and the right part keeps the level layout
+        let mut right = CivSet::with_config_and_comparator(self.config,self.cmp.clone());
+        right.policy = self.policy.clone();
+        let cmp = &self.cmp;
+        let mut i = 0;
+        while i < self.slot.data.len() {
+            match cmp.compare(self.slot.data[i].0.borrow(),k) {
+                Ordering::Less => i += 1,
+                _ => right.slot.data.push(self.slot.data.swap_remove(i)),
+            }
+        }
+        for ms in &mut self.data {
+            let at = ms.data.partition_point(|x| cmp.compare(x.borrow(),k) == Ordering::Less);
+            right.data.push(ms.split_off(at));
+        }
+        while right.data.last().is_some_and(|ms| ms.empty()) {
+            right.data.pop();
+        }
+        self.recount();
+        right.recount();
+        // the split levels keep their capacity
+        for set in [&mut *self,&mut right] {
+            set.check_vacant();
+            set.check_levels();
+        }
+        right
+    }
+    pub fn append(&mut self, other: &mut CivSet<K,C>) {
+        // other is emptied; its keys go with the lowest levels into the first level that fits them,
+        //   for ordered sets this is a concatenation and the levels above are not rewritten
+        if other.len == 0 { return; }
+        if (self.len == 0) && (self.config == other.config) {
+            std::mem::swap(self,other);
+            std::mem::swap(&mut self.policy,&mut other.policy);
+            return;
+        }
+        let run = other.take_sorted();
+        self.merge_run(run);
+    }
+    pub fn reserve(&mut self, additional: usize) {
+        // every level which gets data before len + additional is reached is allocated up front;
+        //   tmp_merge_vec only trade places with the level being merged into and need no memory of their own
+        let n = self.len.checked_add(additional).expect("capacity overflow");
+        let mut lvl = 0;
+        while self.level_capacity(lvl) <= n {
+            if self.data.len() == lvl {
+                self.data.push(SetMultiSlot::new_unallocated(self.level_capacity(lvl)));
+            }
+            let ms = &mut self.data[lvl];
+            ms.data.reserve(ms.capacity - ms.data.len());
+            lvl += 1;
+        }
+    }
+    pub fn config(&self) -> &CivConfig {
+        &self.config
+    }
+    pub fn set_policy<P: CompactionPolicy + 'static>(&mut self, policy: P) {
+        // any level layout is valid for any policy, so it may be changed at any time
+        self.policy = Arc::new(policy);
+    }
+    fn level_capacity(&self, n: usize) -> usize {
+        self.config.level_capacity(self.slot.max_size(),n)
+    }
+    fn recount(&mut self) {
+        // len and tombs from scratch, after levels were changed in bulk
+        self.len = self.slot.len();
+        self.tombs = 0;
+        for ms in &self.data {
+            if ms.empty() { continue; }
+            let live = ms.flags.rank(ms.data.len());
+            self.len += live;
+            self.tombs += ms.capacity - live;
+        }
+    }
+    fn load_sorted(&mut self, data: Vec<K>) {
+        // everything goes into the single level that fits the data
+        self.clear();
+        let sz = self.slot.max_size();
+        let n = data.len();
+        self.len = n;
+        if n < sz {
+            self.slot.data.extend(data.into_iter().map(|k|(k,())));
+            return;
+        }
+        let mut lvl = 0;
+        while self.level_capacity(lvl) < n { lvl += 1; }
+        for i in 0 .. lvl {
+            self.data.push(SetMultiSlot::new_unallocated(self.level_capacity(i)));
+        }
+        self.data.push(SetMultiSlot {
+            capacity: self.level_capacity(lvl),
+            flags: Flags::ones(n),
+            data,
+        });
+        self.tombs = self.level_capacity(lvl) - n;
+    }
+
+    pub fn iter_sorted(&self) -> SetSortedIter<'_,K,C> {
+        SetSortedIter {
+            runs: self.runs(),
+        }
+    }
+    pub fn range<Q: ?Sized, R: RangeBounds<Q>>(&self, range: R) -> SetSortedIter<'_,K,C> where K: Borrow<Q>, C: Comparator<Q> {
+        let mut runs = self.runs();
+        runs.restrict(&range);
+        SetSortedIter {
+            runs,
+        }
+    }
+    pub fn cursor(&self) -> SetCursor<'_,K,C> {
+        SetCursor {
+            runs: self.runs(),
+        }
+    }
+    pub fn floor<Q: ?Sized>(&self, k: &Q) -> Option<&K> where K: Borrow<Q>, C: Comparator<Q> {
+        self.range((Bound::Unbounded,Bound::Included(k))).next_back()
+    }
+    pub fn ceiling<Q: ?Sized>(&self, k: &Q) -> Option<&K> where K: Borrow<Q>, C: Comparator<Q> {
+        self.range((Bound::Included(k),Bound::Unbounded)).next()
+    }
+    pub fn first(&self) -> Option<&K> {
+        self.iter_sorted().next()
+    }
+    pub fn last(&self) -> Option<&K> {
+        self.iter_sorted().next_back()
+    }
+    pub fn rank<Q: ?Sized>(&self, k: &Q) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        // number of keys < k
+        self.runs().rank(k)
+    }
+    pub fn select(&self, n: usize) -> Option<&K> {
+        self.runs().select(n).map(|(k,_)| k)
+    }
+    pub fn union<'t>(&'t self, other: &'t CivSet<K,C>) -> SetUnion<'t,K,C> {
+        SetUnion {
+            a: self.iter_sorted().peekable(),
+            b: other.iter_sorted().peekable(),
+            cmp: &self.cmp,
+        }
+    }
+    pub fn intersection<'t>(&'t self, other: &'t CivSet<K,C>) -> SetIntersection<'t,K,C> {
+        SetIntersection {
+            a: self.iter_sorted().peekable(),
+            b: other.iter_sorted().peekable(),
+            cmp: &self.cmp,
+        }
+    }
+    pub fn difference<'t>(&'t self, other: &'t CivSet<K,C>) -> SetDifference<'t,K,C> {
+        SetDifference {
+            a: self.iter_sorted().peekable(),
+            b: other.iter_sorted().peekable(),
+            cmp: &self.cmp,
+        }
+    }
+    pub fn symmetric_difference<'t>(&'t self, other: &'t CivSet<K,C>) -> SetSymmetricDifference<'t,K,C> {
+        SetSymmetricDifference {
+            a: self.iter_sorted().peekable(),
+            b: other.iter_sorted().peekable(),
+            cmp: &self.cmp,
+        }
+    }
+    pub fn is_subset(&self, other: &CivSet<K,C>) -> bool {
+        if self.len > other.len { return false; }
+        if self.len * 16 < other.len {
+            // few lookups are cheaper than walking the larger set
+            return self.iter().all(|k| other.contains(k));
+        }
+        self.difference(other).next().is_none()
+    }
+    pub fn is_superset(&self, other: &CivSet<K,C>) -> bool {
+        other.is_subset(self)
+    }
+    pub fn is_disjoint(&self, other: &CivSet<K,C>) -> bool {
+        if self.len * 16 < other.len {
+            return !self.iter().any(|k| other.contains(k));
+        }
+        if other.len * 16 < self.len {
+            return !other.iter().any(|k| self.contains(k));
+        }
+        self.intersection(other).next().is_none()
+    }
+    fn runs(&self) -> MergedRuns<'_,K,(),C> {
+        MergedRuns::new(&self.slot,self.data.iter().map(|ms|(&ms.data[..],&ms.flags)).collect(),&self.cmp)
+    }
+    pub fn drain(&mut self) -> SetDrain<'_,K> {
+        // levels are kept allocated, only emptied
+        self.len = 0;
+        self.tombs = 0;
+        SetDrain {
+            slot_iter: self.slot.data.drain(..),
+            cur_data_iter: None,
+            data_iter: self.data.iter_mut(),
+        }
+    }
+
     pub fn clear(&mut self) {
         self.len = 0;
         self.tombs = 0;
@@ -201,44 +933,46 @@ impl<K: Ord> CivSet<K> {
         self.tmp_merge_flags = Flags::tmp();
         self.tmp_merge_vec.clear();
     }
-    pub fn contains(&self, k: &K) -> bool {
-        match self.slot.contains(k) {
+    pub fn contains<Q: ?Sized>(&self, k: &Q) -> bool where K: Borrow<Q>, C: Comparator<Q> {
+        match self.slot.contains(k,&self.cmp) {
             Some(_) => true,
             None => self.multy_contains(k).is_some(),
         }
     }    
-    fn multy_contains(&self, k: &K) -> Option<(usize,usize)> {
+    fn multy_contains<Q: ?Sized>(&self, k: &Q) -> Option<(usize,usize)> where K: Borrow<Q>, C: Comparator<Q> {
         for (n,ms) in self.data.iter().enumerate() {
-            if let Some(idx) = ms.contains(k) {
+            if let Some(idx) = ms.contains(k,&self.cmp) {
                 return Some((n,idx));
             }
         }
         None
     }
+    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&K> where K: Borrow<Q>, C: Comparator<Q> {
+        match self.multy_contains(k) {
+            Some((msi,idx)) => Some(&self.data[msi].data[idx]),
+            None => self.slot.contains(k,&self.cmp).map(|idx| &self.slot.data[idx].0),
+        }
+    }
+    pub fn replace(&mut self, k: K) -> Option<K> {
+        // the stored key is swapped in place, k is inserted if absent
+        if let Some((msi,idx)) = self.multy_contains(&k) {
+            return Some(std::mem::replace(&mut self.data[msi].data[idx],k));
+        }
+        if let Some(idx) = self.slot.contains(&k,&self.cmp) {
+            return Some(std::mem::replace(&mut self.slot.data[idx].0,k));
+        }
+        self.insert(k);
+        None
+    }
     pub fn insert(&mut self, k: K) -> bool {
         // return true if value was inserted
         
         if self.multy_contains(&k).is_some() {
             return false;
         }
-        let (r,filled) = self.slot.insert(k,());
+        let (r,filled) = self.slot.insert(k,(),&self.cmp);
         if let Filled::Full = filled {
-            if self.data.len() == 0 {
-                self.data.push(self.slot.into_set_multislot());
-            } else {
-                let mut n = 0;
-                while (n < self.data.len())&&(!self.data[n].empty()) { n += 1; }
-                if n == self.data.len() {
-                    self.data.push(SetMultiSlot::new_empty(n+1,self.slot.max_size()));
-                }
-                if let Err(s) = self.merge_into(n) {
-                    panic!("Unreachable merge_into: {}",s);
-                }
-                if let Err(s) = self.check_tombs(n) {
-                    panic!("Unreachable check_tombs: {}",s);
-                }
-                self.shrink_long();
-            }
+            self.merge_slot();
         }
         match r {
             None => {
@@ -248,24 +982,210 @@ impl<K: Ord> CivSet<K> {
             Some(_) => false,
         }
     }
+    fn merge_slot(&mut self) {
+        if self.data.len() == 0 {
+            self.data.push(self.slot.into_set_multislot(&self.cmp));
+        } else {
+            let levels: Vec<LevelInfo> = self.data.iter().map(|ms| LevelInfo {
+                capacity: ms.capacity,
+                len: ms.flags.rank(ms.data.len()),
+            }).collect();
+            let range = self.policy.merge_range(self.slot.len(),&levels);
+            let to = (*range.end()).min(levels.len());
+            let from = (*range.start()).min(to);
+            match (from == 0) && self.data.get(to).is_none_or(|ms| ms.empty()) && self.data[..to].iter().all(|ms| !ms.empty()) {
+                true => {
+                    if to == self.data.len() {
+                        self.data.push(SetMultiSlot::new_empty(self.level_capacity(to)));
+                    }
+                    if let Err(s) = self.merge_into(to) {
+                        panic!("Unreachable merge_into: {}",s);
+                    }
+                    if let Err(s) = self.check_tombs(to) {
+                        panic!("Unreachable check_tombs: {}",s);
+                    }
+                },
+                false => {
+                    // an empty level fits the slot and everything below it, so there is always a place
+                    let to = (to + 1).min(levels.len());
+                    let cnt = self.slot.len() + levels[from .. to].iter().map(|l| l.len).sum::<usize>();
+                    let mut n = from;
+                    while (n < self.data.len()) && ((self.data[n].capacity < cnt) || ((n >= to) && !self.data[n].empty())) { n += 1; }
+                    if n == self.data.len() {
+                        self.data.push(SetMultiSlot::new_unallocated(self.level_capacity(n)));
+                    }
+                    self.merge_levels(from .. to,n);
+                },
+            }
+            self.shrink_long();
+        }
+    }
+    fn merge_levels(&mut self, range: Range<usize>, target: usize) {
+        // the slot and the live keys of data[range] are merged into data[target],
+        //   levels of the range may be empty, target is either one of them or an empty level
+        let cmp = &self.cmp;
+        let mut data: Vec<K> = self.slot.sorted_drain(cmp).map(|(k,_)|k).collect();
+        self.slot.clear();
+        for ms in &mut self.data[range] {
+            if ms.empty() { continue; }
+            self.tombs -= ms.capacity - ms.flags.rank(ms.data.len());
+            let run = ms.filtered_drain().collect();
+            data = merge_sorted(data,run,|a,b|cmp.compare(a,b),|a,_|a);
+            ms.clear();
+        }
+        let ms = &mut self.data[target];
+        ms.data.reserve(data.len());
+        ms.data.extend(data);
+        let c = ms.data.len();
+        ms.flags.set_ones(c);
+        self.tombs += ms.capacity - c;
+    }
     pub fn len(&self) -> usize {
         self.len
     }
     pub fn tombs(&self) -> usize {
         self.tombs
     }
-    pub fn remove(&mut self, k: &K) -> bool {
-        let r = match self.multy_contains(&k) {
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> bool where K: Borrow<Q>, C: Comparator<Q> {
+        let r = match self.multy_contains(k) {
             Some((msi,idx)) => {
                 self.tombs += 1;
                 self.data[msi].flags.unset(idx);
+                if self.overfull(msi) {
+                    self.purge_level(msi,Some(idx));
+                }
                 true
             },
-            None => self.slot.remove(k).is_some(),
+            None => self.slot.remove(k,&self.cmp).is_some(),
         };
         if r { self.len -= 1; }
         r
     }
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        // removed level keys become tombstones, their bits are cleared in bulk
+        self.slot.data.retain(|(k,_)| f(k));
+        for ms in &mut self.data {
+            let data = &ms.data;
+            ms.flags.retain(data.len(),|idx| f(&data[idx]));
+        }
+        self.recount();
+        self.check_levels();
+    }
+    fn overfull(&self, msi: usize) -> bool {
+        // removed keys are a notable part of the level
+        let n = self.data[msi].data.len();
+        let dead = n - self.data[msi].flags.rank(n);
+        (dead > self.slot.max_size()) && ((dead as f64) / (n as f64) > self.config.tombs_limit())
+    }
+    fn check_levels(&mut self) {
+        // a level moved down is already purged, so the levels are checked bottom-up
+        for msi in 0 .. self.data.len() {
+            if self.overfull(msi) {
+                self.purge_level(msi,None);
+            }
+        }
+    }
+    fn purge_level(&mut self, msi: usize, take: Option<usize>) -> Option<K> {
+        // tombstones of data[msi] are dropped, the rest moves to the smallest empty level below that fits it;
+        //   the key at take is already unset and is returned
+        let n = self.data[msi].data.len();
+        let live = self.data[msi].flags.rank(n);
+        let to = (0 .. msi).find(|&j| self.data[j].empty() && (self.data[j].capacity >= live)).unwrap_or(msi);
+        let cap = self.data[msi].capacity;
+        let data = std::mem::take(&mut self.data[msi].data);
+        let mut flags = std::mem::replace(&mut self.data[msi].flags,Flags::tmp());
+        let mut res = None;
+        self.data[to].data.reserve(live);
+        for (idx,k) in data.into_iter().enumerate() {
+            if take == Some(idx) {
+                res = Some(k);
+            } else if flags.get(idx) {
+                self.data[to].data.push(k);
+            }
+        }
+        if to == msi {
+            flags.set_nulls();
+            self.data[msi].flags = flags;
+        }
+        // the vacancy moves with the keys, an emptied level has none
+        let left = match live {
+            0 => 0,
+            _ => {
+                self.data[to].flags.set_ones(live);
+                self.data[to].capacity
+            },
+        };
+        self.tombs -= cap - left;
+        res
+    }
+    fn check_vacant(&mut self) {
+        // levels the entries were moved out of keep their capacity, so a level goes down
+        //   once it is too vacant
+        for msi in 0 .. self.data.len() {
+            let ms = &self.data[msi];
+            if ms.empty() { continue; }
+            let live = ms.flags.rank(ms.data.len());
+            let vacant = ms.capacity - live;
+            let over = (vacant > self.slot.max_size()) && ((vacant as f64) / (ms.capacity as f64) > self.config.tombs_limit());
+            if live == 0 {
+                self.purge_level(msi,None);
+            } else if over {
+                // it is merged with the levels below into the lowest level that fits them all,
+                //   which is spread over the empty levels below as after an insert when it is too vacant
+                let cnt = self.slot.len() + self.data[..= msi].iter().map(|ms| ms.flags.rank(ms.data.len())).sum::<usize>();
+                let n = (0 ..= msi).find(|&n| self.data[n].capacity >= cnt).unwrap_or(msi + 1);
+                if self.data.get(n).is_none_or(|ms| (n <= msi) || ms.empty()) {
+                    if n == self.data.len() {
+                        self.data.push(SetMultiSlot::new_unallocated(self.level_capacity(n)));
+                    }
+                    self.merge_levels(0 .. msi+1,n);
+                    if let Err(s) = self.check_tombs(n) {
+                        panic!("Unreachable check_tombs: {}",s);
+                    }
+                }
+            }
+        }
+    }
+    fn dead(&self) -> usize {
+        // removed keys still stored in the levels
+        self.data.iter().fold(0,|acc,ms| acc + ms.data.len() - ms.flags.rank(ms.data.len()))
+    }
+    pub fn compact(&mut self) {
+        // slot and levels are merged into a single tombstone-free level, unused memory is given back
+        let data = self.take_sorted();
+        self.load_sorted(data);
+        self.shrink_to_fit();
+        self.data.shrink_to_fit();
+        self.tmp_merge_vec.shrink_to_fit();
+        self.tmp_merge_flags = Flags::tmp();
+    }
+    pub fn compact_if(&mut self, ratio: f64) -> bool {
+        // compacts if tombstones are more than ratio of the stored keys
+        let dead = self.dead();
+        match (dead > 0) && ((dead as f64) / ((self.len + dead) as f64) > ratio) {
+            true => {
+                self.compact();
+                true
+            },
+            false => false,
+        }
+    }
+    pub fn remove_range<Q: ?Sized, R: RangeBounds<Q>>(&mut self, range: R) -> usize where K: Borrow<Q>, C: Comparator<Q> {
+        // the span of every level is found by binary search and its bits are cleared word-at-a-time
+        let cmp = &self.cmp;
+        let before = self.slot.len();
+        self.slot.data.retain(|(k,_)| !in_range(k.borrow(),&range,cmp));
+        let mut cnt = before - self.slot.len();
+        for ms in &mut self.data {
+            let (from,to) = range_span(&ms.data,&range,cmp);
+            let c = ms.flags.unset_span(from,to);
+            self.tombs += c;
+            cnt += c;
+        }
+        self.len -= cnt;
+        self.check_levels();
+        cnt
+    }
     pub fn shrink_to_fit(&mut self) {
         for ms in &mut self.data {
             ms.shrink_to_fit();
@@ -273,14 +1193,15 @@ impl<K: Ord> CivSet<K> {
     }
     fn shrink_long(&mut self) {
         for ms in &mut self.data {
-            if (ms.capacity >= AUTO_SHRINK_LIMIT)&&(ms.empty()) {   
+            if (ms.capacity >= self.config.shrink_limit())&&(ms.empty()) {   
                 ms.shrink_to_fit();
             }
         }
     }
     fn merge_into(&mut self, n: usize) -> Result<(),&'static str> {
         if !self.data[n].empty() { return Err("data[n] is not empty"); }
-        let mut cnt = self.slot.len();
+        let slot_len = self.slot.len();
+        let mut cnt = slot_len;
         for i in 0 .. n {
             if self.data[i].empty() { return Err("one of data[0..n] is empty"); }
             cnt += self.data[i].data.len();
@@ -306,11 +1227,15 @@ impl<K: Ord> CivSet<K> {
             }
             std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
 
-            self.data[n].data.sort();
+            let cmp = &self.cmp;
+            self.data[n].data.sort_by(|a,b|cmp.compare(a,b));
         }
         
         let c = self.data[n].data.len();
         self.data[n].flags.set_ones(c);
+        // the capacity of data[n] above the slot and data[0..n] is vacant
+        let below: usize = self.data[..n].iter().map(|ms| ms.capacity).sum();
+        self.tombs += self.data[n].capacity - below - slot_len;
         Ok(())
     }
     fn check_tombs(&mut self, n: usize) -> Result<(),&'static str> {
@@ -322,7 +1247,9 @@ impl<K: Ord> CivSet<K> {
         let sz =  self.slot.max_size();
         let local_tombs = self.data[n].capacity - self.data[n].data.len();
         let local_part = (local_tombs as f64) / (self.data[n].capacity as f64);
-        if (local_tombs > sz) && (local_part > TOMBS_LIMIT) {
+        let mut below: usize = self.data[..n].iter().map(|ms| ms.capacity).sum();
+        // with a growth factor above 2 the data may not fit the levels below
+        if (local_tombs > sz) && (local_part > self.config.tombs_limit()) && (self.data[n].data.len() <= below) {
             std::mem::swap(&mut self.data[n].data, &mut self.tmp_merge_vec);
             {
                 let mut count = self.tmp_merge_vec.len();
@@ -331,6 +1258,7 @@ impl<K: Ord> CivSet<K> {
                 let mut msi = self.data[..n].iter_mut();
                 while let Some(ms) = msi.next_back() {
                     let cap = ms.capacity;
+                    below -= cap;
                     if count >= cap {
                         for _ in 0 .. cap {
                             if let Some(k) = iter.next() {
@@ -342,11 +1270,19 @@ impl<K: Ord> CivSet<K> {
                         }
                         ms.flags.set_ones(cap);
                         count -= cap;
-                        if count == 0 { break; }
+                        if count == 0 {
+                            // all the levels are full, the vacancy of data[n] is gone
+                            if local_tombs > self.tombs {
+                                return Err("local_tombs > self.tombs");
+                            }
+                            self.tombs -= local_tombs;
+                            break;
+                        }
                         continue;
                     }
-                    if (cap - count) > sz { continue; }
-                    // checked tombs = (cap - count) <= sz and local_tombs > sz
+                    // the rest goes lower unless it does not fit there
+                    if ((cap - count) > sz) && (count <= below) { continue; }
+                    // checked tombs = (cap - count) <= local_tombs, data[n] fits all the levels below
                     let d_tombs = local_tombs - (cap - count);
                     for _ in 0 .. count {
                         if let Some(k) = iter.next() {
@@ -374,8 +1310,177 @@ impl<K: Ord> CivSet<K> {
     }
 }
 
+impl<K: Clone, C: Comparator<K>> CivSet<K,C> {
+    // level keys stay in place as tombstones, so the key handed out is a clone of the stored one;
+    //   tombstones are reclaimed by the purge of an overfull level
+    pub fn pop_first(&mut self) -> Option<K> {
+        // leading tombstones of the levels are skipped with the rank trees of their flags
+        let pos = self.runs().next().map(|(_,pos)| pos)?;
+        Some(self.take_at(pos))
+    }
+    pub fn pop_last(&mut self) -> Option<K> {
+        let pos = self.runs().next_back().map(|(_,pos)| pos)?;
+        Some(self.take_at(pos))
+    }
+    pub fn take<Q: ?Sized>(&mut self, k: &Q) -> Option<K> where K: Borrow<Q>, C: Comparator<Q> {
+        let pos = match self.multy_contains(k) {
+            Some((msi,idx)) => RunPos::Level(msi,idx),
+            None => RunPos::Slot(self.slot.contains(k,&self.cmp)?),
+        };
+        Some(self.take_at(pos))
+    }
+    pub fn extract_if<F: FnMut(&K) -> bool>(&mut self, pred: F) -> SetExtractIf<'_,K,C,F> {
+        // lazy: keys are visited as they are stored, the slot first and then the levels from the smallest,
+        //   so matching keys come out in no particular order; keys not reached by the iterator are kept
+        SetExtractIf {
+            set: self,
+            pred,
+            slot_idx: 0,
+            msi: 0,
+            idx: 0,
+        }
+    }
+    fn take_at(&mut self, pos: RunPos) -> K {
+        self.len -= 1;
+        match pos {
+            RunPos::Slot(idx) => self.slot.data.swap_remove(idx).0,
+            RunPos::Level(msi,idx) => {
+                self.tombs += 1;
+                self.data[msi].flags.unset(idx);
+                if self.overfull(msi) {
+                    if let Some(k) = self.purge_level(msi,Some(idx)) { return k; }
+                }
+                self.data[msi].data[idx].clone()
+            },
+        }
+    }
+}
+
+impl<K, C: Comparator<K> + Default> Default for CivSet<K,C> {
+    fn default() -> CivSet<K,C> {
+        CivSet::with_comparator(C::default())
+    }
+}
+impl<K, C: Comparator<K> + Default> FromIterator<K> for CivSet<K,C> {
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> CivSet<K,C> {
+        let mut set = CivSet::default();
+        set.load_unsorted(iter.into_iter().collect());
+        set
+    }
+}
+impl<K, C: Comparator<K>> Extend<K> for CivSet<K,C> {
+    fn extend<I: IntoIterator<Item = K>>(&mut self, iter: I) {
+        let batch: Vec<K> = iter.into_iter().collect();
+        if batch.len() < self.slot.max_size() {
+            for k in batch {
+                self.insert(k);
+            }
+            return;
+        }
+        // only the batch is sorted, then it is merged into the levels; present keys are kept as with insert
+        let batch = self.sort_unique(batch);
+        self.merge_run(batch);
+    }
+}
+
+macro_rules! set_operator {
+    ($tr:ident, $func:ident, $iter:ident) => {
+        impl<K: Clone, C: Comparator<K> + Clone> std::ops::$tr<&CivSet<K,C>> for &CivSet<K,C> {
+            type Output = CivSet<K,C>;
+            fn $func(self, other: &CivSet<K,C>) -> CivSet<K,C> {
+                let mut set = CivSet::with_config_and_comparator(self.config,self.cmp.clone());
+                set.policy = self.policy.clone();
+                set.load_sorted(self.$iter(other).cloned().collect());
+                set
+            }
+        }
+    };
+}
+set_operator!(BitOr,bitor,union);
+set_operator!(BitAnd,bitand,intersection);
+set_operator!(Sub,sub,difference);
+set_operator!(BitXor,bitxor,symmetric_difference);
+
+impl<'t,K,C: Comparator<K>> IntoIterator for &'t CivSet<K,C> {
+    type Item = &'t K;
+    type IntoIter = SetIter<'t,K>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+impl<K,C> IntoIterator for CivSet<K,C> {
+    type Item = K;
+    type IntoIter = SetIntoIter<K>;
+    fn into_iter(self) -> Self::IntoIter {
+        SetIntoIter {
+            slot_iter: self.slot.data.into_iter(),
+            cur_data_iter: None,
+            data_iter: self.data.into_iter(),
+        }
+    }
+}
+
+// Comparisons and hashing go over the sorted live keys,
+//   so the slot and level layout does not matter
+impl<K: PartialEq, C: Comparator<K>> PartialEq for CivSet<K,C> {
+    fn eq(&self, other: &CivSet<K,C>) -> bool {
+        (self.len == other.len) && self.iter_sorted().eq(other.iter_sorted())
+    }
+}
+impl<K: Eq, C: Comparator<K>> Eq for CivSet<K,C> {}
+impl<K: PartialOrd, C: Comparator<K>> PartialOrd for CivSet<K,C> {
+    fn partial_cmp(&self, other: &CivSet<K,C>) -> Option<Ordering> {
+        self.iter_sorted().partial_cmp(other.iter_sorted())
+    }
+}
+impl<K: Ord, C: Comparator<K>> Ord for CivSet<K,C> {
+    fn cmp(&self, other: &CivSet<K,C>) -> Ordering {
+        self.iter_sorted().cmp(other.iter_sorted())
+    }
+}
+impl<K: Hash, C: Comparator<K>> Hash for CivSet<K,C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for k in self.iter_sorted() {
+            k.hash(state);
+        }
+    }
+}
+impl<K: PartialEq, C: Comparator<K>> PartialEq<BTreeSet<K>> for CivSet<K,C> {
+    fn eq(&self, other: &BTreeSet<K>) -> bool {
+        (self.len == other.len()) && self.iter_sorted().eq(other.iter())
+    }
+}
+impl<K: PartialEq, C: Comparator<K>> PartialEq<CivSet<K,C>> for BTreeSet<K> {
+    fn eq(&self, other: &CivSet<K,C>) -> bool {
+        other == self
+    }
+}
+
+impl<K: Ord> From<BTreeSet<K>> for CivSet<K> {
+    fn from(set: BTreeSet<K>) -> CivSet<K> {
+        // already sorted and unique
+        CivSet::from_sorted_iter_unchecked(set)
+    }
+}
+impl<K: Ord, S> From<HashSet<K,S>> for CivSet<K> {
+    fn from(set: HashSet<K,S>) -> CivSet<K> {
+        CivSet::from_unsorted_vec(set.into_iter().collect())
+    }
+}
+impl<K: Ord, C: Comparator<K>> From<CivSet<K,C>> for BTreeSet<K> {
+    fn from(mut set: CivSet<K,C>) -> BTreeSet<K> {
+        set.take_sorted().into_iter().collect()
+    }
+}
+impl<K: Eq + Hash, C, S: BuildHasher + Default> From<CivSet<K,C>> for HashSet<K,S> {
+    fn from(set: CivSet<K,C>) -> HashSet<K,S> {
+        set.into_iter().collect()
+    }
+}
+
 #[cfg(feature = "debug")]
-impl<K: Ord> CivSet<K> {
+impl<K, C: Comparator<K>> CivSet<K,C> {
     pub fn check_len(&self) -> usize {
         self.slot.len() + self.data.iter().fold(0,|acc,x|acc+x.check_len())
     }
@@ -403,3 +1508,676 @@ impl<K: Ord> CivSet<K> {
         v
     }
 }
+
+#[cfg(test)]
+impl<K, C: Comparator<K>> CivSet<K,C> {
+    fn check_invariants(&self) {
+        // the stored counters against a recount of the slot and the levels
+        let live = self.slot.len() + self.data.iter().fold(0,|acc,ms| acc + ms.flags.rank(ms.data.len()));
+        let tombs = self.data.iter().filter(|ms| !ms.empty()).fold(0,|acc,ms| acc + ms.capacity - ms.flags.rank(ms.data.len()));
+        assert_eq!(live,self.len());
+        assert_eq!(tombs,self.tombs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::civs::{Tiered,Leveled};
+
+    #[test]
+    fn test_iter_drain() {
+        let cnt = 100_000;
+        let mut res = Vec::with_capacity(cnt);
+        let mut set: CivSet<u64> = CivSet::new();
+        for i in 0 .. cnt {
+            set.insert(i as u64);
+            if i % 10 != 0 {
+                res.push(i as u64);
+            }
+        }
+        for i in 0 .. cnt {
+            if i % 10 == 0 {
+                set.remove(&(i as u64));
+            }
+        }
+
+        let mut lib = set.iter().copied().collect::<Vec<_>>();
+        lib.sort();
+        assert_eq!(res,lib);
+
+        let mut lib = set.clone().into_iter().collect::<Vec<_>>();
+        lib.sort();
+        assert_eq!(res,lib);
+
+        let mut lib = set.drain().take(10).collect::<Vec<_>>();
+        assert_eq!(lib.len(),10);
+        assert_eq!(set.len(),0);
+        assert_eq!(set.iter().next(),None);
+        for i in 0 .. cnt {
+            set.insert(i as u64);
+        }
+        lib = set.drain().collect::<Vec<_>>();
+        lib.sort();
+        assert_eq!(lib,(0 .. cnt as u64).collect::<Vec<_>>());
+        assert_eq!(set.len(),0);
+    }
+
+    #[test]
+    fn test_iter_sorted() {
+        let cnt = 100_000;
+        let mut res = Vec::with_capacity(cnt);
+        let mut set: CivSet<u64> = CivSet::new();
+        for i in 0 .. cnt {
+            set.insert(((i * 7919) % cnt) as u64);
+        }
+        for i in 0 .. cnt {
+            match i % 10 {
+                0 => { set.remove(&(i as u64)); },
+                _ => res.push(i as u64),
+            }
+        }
+
+        let lib = set.iter_sorted().copied().collect::<Vec<_>>();
+        assert_eq!(res,lib);
+
+        res.reverse();
+        let lib = set.iter_sorted().rev().copied().collect::<Vec<_>>();
+        assert_eq!(res,lib);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut set: CivSet<u64> = CivSet::new();
+        let mut ctr = BTreeSet::new();
+        for i in 0 .. 10_000u64 {
+            let k = (i * 7919) % 20_000;
+            set.insert(k);
+            ctr.insert(k);
+        }
+        for i in (0 .. 20_000u64).step_by(3) {
+            set.remove(&i);
+            ctr.remove(&i);
+        }
+        let bounds = [
+            (Bound::Included(100),Bound::Excluded(5_000)),
+            (Bound::Excluded(100),Bound::Included(5_000)),
+            (Bound::Unbounded,Bound::Included(77)),
+            (Bound::Included(19_990),Bound::Unbounded),
+            (Bound::Included(500),Bound::Excluded(500)),
+            (Bound::Unbounded,Bound::Unbounded),
+        ];
+        for b in bounds.iter() {
+            let lib = set.range(*b).copied().collect::<Vec<_>>();
+            let res = ctr.range(*b).copied().collect::<Vec<_>>();
+            assert_eq!(res,lib);
+            let lib = set.range(*b).rev().copied().collect::<Vec<_>>();
+            let res = ctr.range(*b).rev().copied().collect::<Vec<_>>();
+            assert_eq!(res,lib);
+        }
+        assert!(set.range(1_000 .. 2_000).eq(ctr.range(1_000 .. 2_000)));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut set: CivSet<u64> = CivSet::new();
+        for i in 0 .. 5_000u64 {
+            set.insert(i * 10);
+        }
+        for i in (0 .. 5_000u64).step_by(2) {
+            set.remove(&(i * 10));
+        }
+
+        // missing keys and removed keys
+        assert_eq!(set.floor(&25),Some(&10));
+        assert_eq!(set.ceiling(&25),Some(&30));
+        assert_eq!(set.floor(&20),Some(&10));
+        assert_eq!(set.ceiling(&20),Some(&30));
+        assert_eq!(set.floor(&30),Some(&30));
+        assert_eq!(set.ceiling(&30),Some(&30));
+        // both ends
+        assert_eq!(set.floor(&0),None);
+        assert_eq!(set.floor(&9),None);
+        assert_eq!(set.ceiling(&0),Some(&10));
+        assert_eq!(set.floor(&u64::MAX),Some(&49_990));
+        assert_eq!(set.ceiling(&49_990),Some(&49_990));
+        assert_eq!(set.ceiling(&49_991),None);
+
+        let mut cursor = set.cursor();
+        assert_eq!(cursor.peek_prev(),None);
+        cursor.seek(&1_000);
+        assert_eq!(cursor.peek_prev(),Some(&990));
+        assert_eq!(cursor.peek_next(),Some(&1_010));
+        assert_eq!(cursor.next(),Some(&1_010));
+        assert_eq!(cursor.next(),Some(&1_030));
+        assert_eq!(cursor.prev(),Some(&1_030));
+        assert_eq!(cursor.prev(),Some(&1_010));
+        assert_eq!(cursor.prev(),Some(&990));
+        // seek forward and back again
+        cursor.seek(&30_000);
+        assert_eq!(cursor.peek_next(),Some(&30_010));
+        cursor.seek(&555);
+        assert_eq!(cursor.peek_prev(),Some(&550));
+        assert_eq!(cursor.next(),Some(&570));
+        cursor.seek(&0);
+        assert_eq!(cursor.peek_prev(),None);
+        assert_eq!(cursor.next(),Some(&10));
+        cursor.seek(&50_000);
+        assert_eq!(cursor.peek_next(),None);
+        assert_eq!(cursor.prev(),Some(&49_990));
+        cursor.seek_last();
+        assert_eq!(cursor.next(),None);
+        assert_eq!(cursor.prev(),Some(&49_990));
+        cursor.seek_first();
+        assert_eq!(cursor.prev(),None);
+        assert_eq!(cursor.count(),2_500);
+    }
+
+    #[test]
+    fn test_pop() {
+        let cnt = 100_000u64;
+        let mut set: CivSet<u64> = (0 .. cnt).collect();
+        for i in (0 .. cnt).step_by(10) {
+            set.remove(&i);
+        }
+        assert_eq!(set.first(),Some(&1));
+        assert_eq!(set.last(),Some(&(cnt - 1)));
+        assert_eq!(set.pop_first(),Some(1));
+        assert_eq!(set.pop_first(),Some(2));
+        assert_eq!(set.pop_last(),Some(cnt - 1));
+        assert_eq!(set.len(),89_997);
+        let mut ctrl = (3 .. cnt - 1).filter(|i| i % 10 != 0).collect::<Vec<_>>();
+        for i in 0 .. 30_000 {
+            match i % 3 {
+                0 => assert_eq!(set.pop_last(),ctrl.pop()),
+                _ => assert_eq!(set.pop_first(),Some(ctrl.remove(0))),
+            }
+        }
+        assert!(set.iter_sorted().eq(ctrl.iter()));
+        set.check_invariants();
+        assert!(set.tombs() < set.len());
+
+        // a work queue: the front of the levels fills with tombstones until they are purged
+        let mut set: CivSet<u64> = (0 .. cnt).collect();
+        let mut ctrl: BTreeSet<u64> = (0 .. cnt).collect();
+        for i in 0 .. 50_000u64 {
+            set.insert(cnt + i * 7 % 1_000);
+            ctrl.insert(cnt + i * 7 % 1_000);
+            assert_eq!(set.pop_first(),ctrl.pop_first());
+            assert_eq!(set.first(),ctrl.first());
+        }
+        assert_eq!(set,ctrl);
+        set.check_invariants();
+
+        #[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+        struct Key(u64);
+        let mut set: CivSet<Key> = (0 .. 1_000u64).map(Key).collect();
+        for i in (0 .. 1_000u64).step_by(3) {
+            set.remove(&Key(i));
+        }
+        assert_eq!(set.pop_first(),Some(Key(1)));
+        assert_eq!(set.pop_last(),Some(Key(998)));
+        assert_eq!(set.pop_last(),Some(Key(997)));
+        assert_eq!(set.len(),663);
+        assert!(set.iter_sorted().map(|k|k.0).eq((2 .. 997u64).filter(|i| i % 3 != 0)));
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut set: CivSet<u64> = CivSet::new();
+        for i in 0 .. 20_000u64 {
+            set.insert((i * 7919) % 20_000);
+        }
+        for i in (0 .. 20_000u64).filter(|i| (i % 3 == 0)||(i % 1000 < 200)) {
+            set.remove(&i);
+        }
+        let sorted = set.iter_sorted().copied().collect::<Vec<_>>();
+        for (n,k) in sorted.iter().enumerate() {
+            assert_eq!(set.rank(k),n);
+            assert_eq!(set.select(n),Some(k));
+        }
+        // a removed key counts the live keys below it
+        assert_eq!(set.rank(&1_002),sorted.iter().filter(|k| **k < 1_002).count());
+        assert_eq!(set.rank(&0),0);
+        assert_eq!(set.rank(&20_000),sorted.len());
+        assert_eq!(set.select(sorted.len()),None);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        assert!(matches!(CivSet::from_sorted_iter(vec![1u64,3,2]),Err(CivSetBuildError::Unsorted(2))));
+        assert!(matches!(CivSet::from_sorted_iter(vec![1u64,1]),Err(CivSetBuildError::Duplicate(1))));
+
+        for cnt in [10u64,64,1_000,100_000].iter() {
+            let mut set = CivSet::from_sorted_iter((0 .. *cnt).map(|i| i * 2)).unwrap();
+            let mut ctr = (0 .. *cnt).map(|i| i * 2).collect::<BTreeSet<_>>();
+            assert_eq!(set.len(),ctr.len());
+            for i in 0 .. 10_000u64 {
+                let k = (i * 7919) % (3 * cnt);
+                assert_eq!(set.insert(k),ctr.insert(k));
+                if i % 3 == 0 {
+                    assert_eq!(set.remove(&(k/2)),ctr.remove(&(k/2)));
+                }
+            }
+            assert!(set.iter_sorted().eq(ctr.iter()));
+        }
+
+        let cnt = 100_000u64;
+        let mut set = CivSet::from_unsorted_vec((0 .. cnt).rev().filter(|i| i % 10 != 0).collect());
+        assert!(set.iter_sorted().copied().eq((0 .. cnt).filter(|i| i % 10 != 0)));
+        for i in (0 .. cnt).step_by(10) {
+            assert!(set.insert(i));
+        }
+        assert_eq!(set.len(),cnt as usize);
+        assert!(set.iter_sorted().copied().eq(0 .. cnt));
+
+        let set = CivSet::from_unsorted_vec(vec![5u64,3,5,1,3,5]);
+        assert_eq!(set.iter_sorted().copied().collect::<Vec<_>>(),vec![1,3,5]);
+    }
+
+    #[test]
+    fn test_collect_extend() {
+        let mut set = (0 .. 1_000u64).map(|i| i * 3).collect::<CivSet<_>>();
+        let mut ctr = (0 .. 1_000u64).map(|i| i * 3).collect::<BTreeSet<_>>();
+        assert!(set.contains(&30));
+
+        set.extend((0 .. 10u64).map(|i| i * 5));
+        ctr.extend((0 .. 10u64).map(|i| i * 5));
+        set.extend((0 .. 5_000u64).map(|i| i * 2));
+        ctr.extend((0 .. 5_000u64).map(|i| i * 2));
+        assert_eq!(set.len(),ctr.len());
+        assert!(set.iter_sorted().eq(ctr.iter()));
+
+        // a batch smaller than the set is merged into the lowest levels only
+        let top = set.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = set.data[top].data.as_ptr();
+        set.extend((0 .. 1_000u64).rev().map(|i| i * 7));
+        ctr.extend((0 .. 1_000u64).rev().map(|i| i * 7));
+        assert_eq!(set.data[top].data.as_ptr(),ptr);
+        assert_eq!(set,ctr);
+
+        let set: CivSet<u64> = Default::default();
+        assert_eq!(set.len(),0);
+    }
+
+    #[test]
+    fn test_borrow() {
+        let mut set: CivSet<String> = CivSet::new();
+        for i in 0 .. 1_000u32 {
+            set.insert(format!("key{}",i));
+        }
+        assert!(set.contains("key10"));
+        assert_eq!(set.get("key500").map(|k| k.as_str()),Some("key500"));
+        assert!(set.remove("key7"));
+        assert!(!set.remove("key7"));
+        assert!(!set.contains("key7"));
+        // the last keys are still in the slot, the first ones in the levels
+        assert_eq!(set.take("key999"),Some("key999".to_string()));
+        assert_eq!(set.take("key3"),Some("key3".to_string()));
+        assert_eq!(set.take("key3"),None);
+        assert_eq!(set.len(),997);
+        assert_eq!(set.range::<str,_>((Bound::Included("key10"),Bound::Excluded("key11"))).count(),11);
+        assert_eq!(set.ceiling("key99z"),None);
+        assert_eq!(set.rank("key1"),1);
+    }
+
+    #[test]
+    fn test_comparator() {
+        let mut set = CivSet::with_comparator(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+        for i in 0 .. 1_000 {
+            assert!(set.insert(format!("Key{:04}",i)));
+        }
+        assert!(!set.insert("KEY0010".to_string()));
+        assert!(set.contains(&"key0999".to_string()));
+        assert!(set.remove(&"kEy0500".to_string()));
+        assert_eq!(set.len(),999);
+        assert_eq!(set.first().map(|k|k.as_str()),Some("Key0000"));
+        assert_eq!(set.ceiling(&"key0500".to_string()).map(|k|k.as_str()),Some("Key0501"));
+        assert!(set.iter_sorted().zip(set.iter_sorted().skip(1)).all(|(a,b)|a.to_lowercase() < b.to_lowercase()));
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        use std::collections::BTreeSet;
+        let a: CivSet<u64> = (0 .. 20_000u64).filter(|i| i % 2 == 0).collect();
+        let mut b: CivSet<u64> = CivSet::new();
+        for i in (0 .. 30_000u64).filter(|i| i % 3 == 0) {
+            b.insert(i);
+        }
+        for i in (0 .. 30_000u64).filter(|i| i % 15 == 0) {
+            b.remove(&i);
+        }
+        let ca: BTreeSet<u64> = a.iter().cloned().collect();
+        let cb: BTreeSet<u64> = b.iter().cloned().collect();
+
+        assert!(a.union(&b).eq(ca.union(&cb)));
+        assert!(a.intersection(&b).eq(ca.intersection(&cb)));
+        assert!(a.difference(&b).eq(ca.difference(&cb)));
+        assert!(b.difference(&a).eq(cb.difference(&ca)));
+        assert!(a.symmetric_difference(&b).eq(ca.symmetric_difference(&cb)));
+
+        let u = &a | &b;
+        assert_eq!(u.len(),ca.union(&cb).count());
+        assert!(u.iter_sorted().eq(ca.union(&cb)));
+        assert!((&a & &b).iter_sorted().eq(ca.intersection(&cb)));
+        assert!((&a - &b).iter_sorted().eq(ca.difference(&cb)));
+        assert!((&a ^ &b).iter_sorted().eq(ca.symmetric_difference(&cb)));
+
+        assert!(a.is_subset(&u) && b.is_subset(&u) && u.is_superset(&a));
+        assert!(!a.is_subset(&b) && !u.is_subset(&a));
+        assert!(!a.is_disjoint(&b));
+        assert!((&a - &b).is_disjoint(&b));
+        let small: CivSet<u64> = [6,12,18].into_iter().collect();
+        assert!(small.is_subset(&a) && !small.is_disjoint(&b));
+        assert!(!b.is_disjoint(&small) && (&b - &small).is_disjoint(&small));
+    }
+
+    #[test]
+    fn test_absorb() {
+        let mut set: CivSet<u64> = (0 .. 50_000u64).filter(|i| i % 2 == 0).collect();
+        for i in 0 .. 1_000u64 {
+            set.remove(&(i * 4));
+        }
+        let mut other = CivSet::new();
+        for i in (0 .. 50_000u64).filter(|i| i % 3 == 0) {
+            other.insert(i);
+        }
+        let res: Vec<u64> = set.union(&other).cloned().collect();
+        set.absorb(other);
+        assert_eq!(set.len(),res.len());
+        assert!(set.iter_sorted().eq(res.iter()));
+        set.absorb(CivSet::new());
+        assert_eq!(set.len(),res.len());
+        assert!(set.insert(1));
+
+        // a small set only rewrites the lowest levels
+        let mut ctrl: BTreeSet<u64> = set.iter().copied().collect();
+        let top = set.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = set.data[top].data.as_ptr();
+        set.absorb((49_990 .. 50_100u64).collect());
+        ctrl.extend(49_990 .. 50_100u64);
+        assert_eq!(set.data[top].data.as_ptr(),ptr);
+        assert_eq!(set,ctrl);
+    }
+
+    #[test]
+    fn test_split_append() {
+        use std::collections::BTreeSet;
+        let mut set: CivSet<u64> = CivSet::new();
+        let mut ctrl = BTreeSet::new();
+        for i in 0 .. 100_000u64 {
+            let k = (i * 7919) % 100_000;
+            set.insert(k);
+            ctrl.insert(k);
+            if i % 5 == 0 {
+                set.remove(&(i / 2));
+                ctrl.remove(&(i / 2));
+            }
+        }
+        let mut right = set.split_off(&65_000);
+        let mut ctrl_right = ctrl.split_off(&65_000);
+        // the split levels do not stay vacant
+        for half in [&set,&right] {
+            assert!((half.tombs() as f64) <= half.config.tombs_limit() * (half.len() as f64));
+            half.check_invariants();
+        }
+        assert!(set.iter_sorted().eq(ctrl.iter()));
+        assert!(right.iter_sorted().eq(ctrl_right.iter()));
+        for i in 0 .. 10_000u64 {
+            assert_eq!(set.insert(i * 3),ctrl.insert(i * 3));
+            assert_eq!(right.remove(&(65_000 + i)),ctrl_right.remove(&(65_000 + i)));
+        }
+        assert_eq!((set.len(),right.len()),(ctrl.len(),ctrl_right.len()));
+        set.append(&mut right);
+        ctrl.append(&mut ctrl_right);
+        assert_eq!(right.len(),0);
+        assert_eq!(set.len(),ctrl.len());
+        assert!(set.iter_sorted().eq(ctrl.iter()));
+
+        // an ordered append goes into the lowest levels only
+        let top = set.data.iter().rposition(|ms| !ms.empty()).unwrap();
+        let ptr = set.data[top].data.as_ptr();
+        let mut tail: CivSet<u64> = (200_000 .. 205_000u64).collect();
+        set.append(&mut tail);
+        ctrl.extend(200_000 .. 205_000u64);
+        assert_eq!(tail.len(),0);
+        assert_eq!(set.data[top].data.as_ptr(),ptr);
+        assert!(set.iter_sorted().eq(ctrl.iter()));
+    }
+
+    #[test]
+    fn test_eq_hash_ord() {
+        let mut a: CivSet<u64> = CivSet::new();
+        for i in (0 .. 5_000u64).rev() {
+            a.insert(i);
+        }
+        for i in 0 .. 1_000u64 {
+            a.remove(&(i * 5));
+        }
+        let ctrl: BTreeSet<u64> = (0 .. 5_000u64).filter(|i| (i % 5 != 0) || (*i >= 5_000)).collect();
+        let b: CivSet<u64> = ctrl.iter().cloned().collect();
+        assert_eq!(a,b);
+        assert_eq!(a,ctrl);
+        assert_eq!(ctrl,a);
+        assert_eq!(a.cmp(&b),Ordering::Equal);
+
+        let mut sets = HashSet::new();
+        sets.insert(a.clone());
+        assert!(sets.contains(&b));
+        a.insert(0);
+        assert!(!sets.contains(&a));
+        assert!(a < b);
+        assert_ne!(a,ctrl);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let ctrl: BTreeSet<u64> = (0 .. 10_000u64).map(|i| i * 7 % 10_007).collect();
+        let mut set = CivSet::from(ctrl.clone());
+        assert_eq!(set,ctrl);
+        set.remove(&7);
+        set.insert(10_008);
+        let mut ctrl = ctrl;
+        ctrl.remove(&7);
+        ctrl.insert(10_008);
+        assert_eq!(BTreeSet::from(set.clone()),ctrl);
+        let hset: HashSet<u64> = set.clone().into();
+        assert_eq!(hset.len(),ctrl.len());
+        assert!(ctrl.iter().all(|k| hset.contains(k)));
+        assert_eq!(CivSet::from(hset),set);
+    }
+
+    #[test]
+    fn test_get_replace_take() {
+        #[derive(Debug,Clone)]
+        struct Item {
+            id: u64,
+            payload: u64,
+        }
+        impl PartialEq for Item {
+            fn eq(&self, other: &Item) -> bool { self.id == other.id }
+        }
+        impl Eq for Item {}
+        impl PartialOrd for Item {
+            fn partial_cmp(&self, other: &Item) -> Option<Ordering> { Some(self.cmp(other)) }
+        }
+        impl Ord for Item {
+            fn cmp(&self, other: &Item) -> Ordering { self.id.cmp(&other.id) }
+        }
+        let key = |id| Item { id, payload: 0 };
+
+        let mut set = CivSet::new();
+        for id in 0 .. 10_000u64 {
+            set.insert(Item { id, payload: id });
+        }
+        // 9_999 is in the slot, 0 is in a level
+        for id in [0,5_000,9_999] {
+            assert_eq!(set.get(&key(id)).map(|it|it.payload),Some(id));
+            assert_eq!(set.replace(Item { id, payload: 1 }).map(|it|it.payload),Some(id));
+            assert_eq!(set.get(&key(id)).map(|it|it.payload),Some(1));
+        }
+        assert!(set.replace(Item { id: 20_000, payload: 2 }).is_none());
+        assert_eq!(set.len(),10_001);
+
+        assert_eq!(set.take(&key(5_000)).map(|it|it.payload),Some(1));
+        assert_eq!(set.take(&key(20_000)).map(|it|it.payload),Some(2));
+        assert_eq!(set.take(&key(5_000)),None);
+        assert_eq!(set.get(&key(5_000)),None);
+        assert_eq!(set.len(),9_999);
+        assert!(set.insert(Item { id: 5_000, payload: 3 }));
+        assert_eq!(set.get(&key(5_000)).map(|it|it.payload),Some(3));
+
+        for id in (0 .. 10_000u64).rev().step_by(3) {
+            assert_eq!(set.take(&key(id)).map(|it|it.id),Some(id));
+        }
+        assert!(set.iter_sorted().map(|it|it.id).eq((0 .. 10_000u64).filter(|id| (9_999 - id) % 3 != 0)));
+        set.check_invariants();
+    }
+
+    #[test]
+    fn test_retain_extract() {
+        let mut set: CivSet<u64> = CivSet::new();
+        let mut ctrl = BTreeSet::new();
+        for i in 0 .. 50_000u64 {
+            set.insert(i * 11 % 50_000);
+            ctrl.insert(i * 11 % 50_000);
+        }
+        set.retain(|k| k % 10 != 0);
+        ctrl.retain(|k| k % 10 != 0);
+        assert_eq!(set,ctrl);
+        set.retain(|k| k % 2 == 0);
+        ctrl.retain(|k| k % 2 == 0);
+        assert_eq!(set,ctrl);
+        assert!(set.tombs() < set.len());
+
+        let mut out: Vec<u64> = set.extract_if(|k| k % 3 == 0).collect();
+        out.sort();
+        let ctrl_out: Vec<u64> = ctrl.extract_if(..,|k| k % 3 == 0).collect();
+        assert_eq!(out,ctrl_out);
+        assert_eq!(set,ctrl);
+        // keys the iterator did not reach stay in the set
+        let first: Vec<u64> = set.extract_if(|k| k % 7 == 0).take(100).collect();
+        assert_eq!(first.len(),100);
+        for k in &first {
+            assert!(ctrl.remove(k));
+        }
+        assert_eq!(set,ctrl);
+        set.check_invariants();
+        for i in 0 .. 1_000u64 {
+            assert_eq!(set.insert(i * 3),ctrl.insert(i * 3));
+        }
+        assert_eq!(set,ctrl);
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut set: CivSet<u64> = (0 .. 20_000u64).filter(|i| i % 3 != 0).collect();
+        let mut ctrl: BTreeSet<u64> = set.iter().cloned().collect();
+        for i in 20_000 .. 20_100u64 {
+            set.insert(i);
+            ctrl.insert(i);
+        }
+        assert_eq!(set.remove_range(19_950 .. 20_050),83);
+        ctrl.retain(|k| !(19_950 .. 20_050).contains(k));
+        assert_eq!(set.remove_range(100 ..= 199),67);
+        ctrl.retain(|k| !(100 ..= 199).contains(k));
+        assert_eq!(set,ctrl);
+        assert_eq!(set.rank(&10_000),ctrl.range(.. 10_000).count());
+        for i in 0 .. 300u64 {
+            assert_eq!(set.insert(i),ctrl.insert(i));
+        }
+        assert_eq!(set,ctrl);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = CivConfig::builder().slot_size(8).growth_factor(3).build().unwrap();
+        let mut set = CivSet::with_config(config);
+        let mut ctrl = BTreeSet::new();
+        for i in 0 .. 50_000u64 {
+            let k = i * 7919 % 10_000;
+            match i % 4 {
+                0 => assert_eq!(set.remove(&k),ctrl.remove(&k)),
+                _ => assert_eq!(set.insert(k),ctrl.insert(k)),
+            }
+        }
+        assert_eq!(set,ctrl);
+        set.check_invariants();
+        let u = &set | &CivSet::new();
+        assert_eq!(u.config(),&config);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut set: CivSet<u64> = CivSet::new();
+        set.insert(1);
+        set.reserve(20_000);
+        let caps: Vec<usize> = set.data.iter().map(|ms| ms.data.capacity()).collect();
+        for i in 0 .. 20_000u64 {
+            set.insert(i);
+        }
+        assert_eq!(set.len(),20_000);
+        assert_eq!(caps,set.data.iter().map(|ms| ms.data.capacity()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_compact() {
+        // removals alone never purge the levels with this limit
+        let mut set: CivSet<u64> = CivSet::with_config(CivConfig::builder().tombs_limit(1.0).build().unwrap());
+        for i in 0 .. 60_000u64 {
+            set.insert(i);
+        }
+        assert!(!set.compact_if(0.0));
+        for i in 0 .. 60_000u64 {
+            if i % 2 == 1 { set.remove(&i); }
+        }
+        assert!(set.compact_if(0.4));
+        assert_eq!(set.dead(),0);
+        assert!(set.iter_sorted().cloned().eq((0 .. 60_000u64).step_by(2)));
+        assert_eq!(set.data.iter().map(|ms| ms.data.capacity()).sum::<usize>(),set.len());
+    }
+
+    #[test]
+    fn test_remove_purge() {
+        let mut set: CivSet<u64> = (0 .. 200_000u64).collect();
+        let stored = set.len() + set.tombs();
+        for i in 0 .. 190_000u64 {
+            match i % 2 {
+                0 => assert!(set.remove(&(i * 7919 % 200_000))),
+                _ => assert!(set.take(&(i * 7919 % 200_000)).is_some()),
+            }
+        }
+        set.check_invariants();
+        assert!(set.tombs() < stored / 10);
+        assert_eq!(set.len(),10_000);
+        let ctrl: BTreeSet<u64> = (190_000 .. 200_000u64).map(|i| i * 7919 % 200_000).collect();
+        assert_eq!(set,ctrl);
+    }
+
+    #[test]
+    fn test_policy() {
+        fn check<P: CompactionPolicy + 'static>(policy: P) -> usize {
+            let mut set: CivSet<u64> = CivSet::new();
+            set.set_policy(policy);
+            let mut ctrl = BTreeSet::new();
+            let mut max_runs = 0;
+            for i in 0 .. 100_000u64 {
+                let k = i * 7919 % 60_000;
+                match i % 4 {
+                    3 => assert_eq!(set.remove(&k),ctrl.remove(&k)),
+                    _ => assert_eq!(set.insert(k),ctrl.insert(k)),
+                }
+                max_runs = max_runs.max(set.data.iter().filter(|ms| !ms.empty()).count());
+            }
+            set.check_invariants();
+            assert_eq!(set,ctrl);
+            let union = &set | &CivSet::from_iter(60_000 .. 61_000u64);
+            assert_eq!(union.len(),ctrl.len() + 1_000);
+            max_runs
+        }
+        let binary = check(BinaryCounter);
+        // fewer runs cost more merges and the other way round
+        assert!(check(Leveled::new(4)) < binary);
+        assert!(check(Tiered::new(4)) > binary);
+    }
+}
@@ -1,11 +1,13 @@
-use serde::{Serialize,Deserialize};
+use serde::{Serialize,Serializer};
 use std::io::{Write,Read};
+use std::cmp::Ordering;
 
 mod civs;
 
 pub use crate::civs::{
-    set::{CivSet,CivSetIoError},
-    map::{CivMap,CivMapIoError,RemovedItem,Iter},
+    CivConfig,CivConfigBuilder,CivConfigError,CompactionPolicy,LevelInfo,BinaryCounter,Tiered,Leveled,
+    set::{CivSet,CivSetIoError,CivSetBuildError,SetIter,SetSortedIter,SetCursor,SetIntoIter,SetDrain,SetExtractIf,SetUnion,SetIntersection,SetDifference,SetSymmetricDifference},
+    map::{CivMap,CivMapIoError,CivMapBuildError,RemovedItem,Iter,SortedIter,Cursor,ExtractIf,Entry,OccupiedEntry,VacantEntry},
 };
 
 
@@ -16,6 +18,32 @@ pub trait Binary: Sized {
     fn from_reader<R: Read>(rdr: R) -> Result<Self,Self::IoError>;
 }
 
+pub trait Comparator<K: ?Sized> {
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct NaturalOrder;
+impl<K: ?Sized + Ord> Comparator<K> for NaturalOrder {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct ReverseOrder;
+impl<K: ?Sized + Ord> Comparator<K> for ReverseOrder {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+impl<K: ?Sized, F: Fn(&K,&K) -> Ordering> Comparator<K> for F {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        self(a,b)
+    }
+}
+
 
 #[derive(Debug)]
 enum Filled {
@@ -23,24 +51,35 @@ enum Filled {
     Full,
 }
 
-#[derive(Debug,Clone,Serialize,Deserialize)]
-struct Flags(Vec<u64>);
+// bits and a fenwick tree over popcounts of the bit words (for rank/select)
+#[derive(Debug,Clone)]
+struct Flags(Vec<u64>,Vec<u64>);
+impl Serialize for Flags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("Flags", &self.0)
+    }
+}
 impl Flags {
     fn heap_mem(&self) -> usize {
-        self.0.capacity() * std::mem::size_of::<u64>()
+        (self.0.capacity() + self.1.capacity()) * std::mem::size_of::<u64>()
     }
     fn tmp() -> Flags {
-        Flags(vec![])
+        Flags(vec![],vec![])
+    }
+    fn from_bits(bits: Vec<u64>) -> Flags {
+        let mut flags = Flags(bits,Vec::new());
+        flags.build_ranks();
+        flags
     }
     fn nulls(sz: usize) -> Flags {
-        if sz == 0 { return Flags(Vec::new()); }
+        if sz == 0 { return Flags(Vec::new(),Vec::new()); }
         let ln = 1 + (sz-1)/64;
         let mut v = Vec::with_capacity(ln);
         for _ in 0 .. ln { v.push(0); }
-        Flags(v)
+        Flags(v.clone(),v)
     }
     fn ones(sz: usize) -> Flags {
-        if sz == 0 { return Flags(Vec::new()); }
+        if sz == 0 { return Flags(Vec::new(),Vec::new()); }
         let ln = 1 + (sz-1)/64;
         let mut v = Vec::with_capacity(ln);
         let mut s = sz;
@@ -53,12 +92,15 @@ impl Flags {
             if s > 64 { s -= 64; } else { s = 0; } 
         }
         while v.len() < ln { v.push(0); }
-        Flags(v)
+        Flags::from_bits(v)
     }
     fn set_nulls(&mut self) {
         for i in 0 .. self.0.len() {
             self.0[i] = 0;
         }
+        for r in &mut self.1 {
+            *r = 0;
+        }
     }
     fn set_ones(&mut self, sz: usize) {
         let ln = 1 + (sz-1)/64;
@@ -72,6 +114,132 @@ impl Flags {
             }
             if s > 64 { s -= 64; } else { s = 0; } 
         }
+        self.build_ranks();
+    }
+    fn build_ranks(&mut self) {
+        let n = self.0.len();
+        self.1.clear();
+        self.1.extend(self.0.iter().map(|w| w.count_ones() as u64));
+        for i in 1 ..= n {
+            let j = i + (i & i.wrapping_neg());
+            if j <= n {
+                self.1[j-1] += self.1[i-1];
+            }
+        }
+    }
+    fn dec_rank(&mut self, word: usize, cnt: u64) {
+        let n = self.1.len();
+        let mut i = word + 1;
+        while i <= n {
+            self.1[i-1] -= cnt;
+            i += i & i.wrapping_neg();
+        }
+    }
+    // number of set bits in [0,idx)
+    fn rank(&self, idx: usize) -> usize {
+        let w = idx/64;
+        let j = idx%64;
+        let mut i = w;
+        let mut r = 0;
+        while i > 0 {
+            r += self.1[i-1] as usize;
+            i &= i - 1;
+        }
+        if (j > 0) && (w < self.0.len()) {
+            r += (self.0[w] & (0xFFFFFFFFFFFFFFFFu64 >> (64 - j))).count_ones() as usize;
+        }
+        r
+    }
+    // index of the n-th (from 0) set bit
+    fn select(&self, n: usize) -> Option<usize> {
+        let len = self.1.len();
+        let mut step = match len {
+            0 => return None,
+            _ => 1 << (usize::BITS - 1 - len.leading_zeros()),
+        };
+        let mut pos = 0;
+        let mut rem = n as u64;
+        while step > 0 {
+            if (pos + step <= len) && (self.1[pos+step-1] <= rem) {
+                pos += step;
+                rem -= self.1[pos-1];
+            }
+            step >>= 1;
+        }
+        if pos >= len { return None; }
+        let mut w = self.0[pos];
+        for _ in 0 .. rem {
+            w &= w - 1;
+        }
+        match w {
+            0 => None,
+            _ => Some(pos * 64 + w.trailing_zeros() as usize),
+        }
+    }
+    // bits [at,len) are moved into the returned flags, self keeps [0,at)
+    fn split_off(&mut self, at: usize, len: usize) -> Flags {
+        if at >= len { return Flags::tmp(); }
+        let n = len - at;
+        let w = at/64;
+        let j = at%64;
+        let mut bits = Vec::with_capacity(1 + (n-1)/64);
+        for i in 0 .. 1 + (n-1)/64 {
+            let mut v = self.0[w+i] >> j;
+            if (j > 0) && (w+i+1 < self.0.len()) {
+                v |= self.0[w+i+1] << (64 - j);
+            }
+            bits.push(v);
+        }
+        let t = n%64;
+        if t > 0 {
+            if let Some(v) = bits.last_mut() { *v &= 0xFFFFFFFFFFFFFFFFu64 >> (64 - t); }
+        }
+        self.0[w] &= match j {
+            0 => 0,
+            _ => 0xFFFFFFFFFFFFFFFFu64 >> (64 - j),
+        };
+        for v in &mut self.0[w+1 ..] {
+            *v = 0;
+        }
+        self.build_ranks();
+        Flags::from_bits(bits)
+    }
+    // clears the set bits in [0,len) for which keep(idx) is false, returns their count
+    fn retain<F: FnMut(usize) -> bool>(&mut self, len: usize, mut keep: F) -> usize {
+        let mut cnt = 0;
+        for i in 0 .. self.0.len().min(len.div_ceil(64)) {
+            let mut w = self.0[i];
+            while w > 0 {
+                let j = w.trailing_zeros() as usize;
+                w &= w - 1;
+                if i*64 + j >= len { break; }
+                if !keep(i*64 + j) {
+                    self.0[i] &= !(0x1u64 << j);
+                    cnt += 1;
+                }
+            }
+        }
+        if cnt > 0 { self.build_ranks(); }
+        cnt
+    }
+    // clears the bits in [from,to) word-at-a-time, returns the number of cleared set bits
+    fn unset_span(&mut self, from: usize, to: usize) -> usize {
+        let mut cnt = 0;
+        let mut idx = from;
+        while idx < to {
+            let i = idx/64;
+            let j = idx%64;
+            let n = (64 - j).min(to - idx);
+            let mask = (0xFFFFFFFFFFFFFFFFu64 >> (64 - n)) << j;
+            let c = (self.0[i] & mask).count_ones() as u64;
+            if c > 0 {
+                self.0[i] &= !mask;
+                self.dec_rank(i,c);
+                cnt += c as usize;
+            }
+            idx += n;
+        }
+        cnt
     }
     #[inline]
     fn get(&self, idx: usize) -> bool {
@@ -83,7 +251,10 @@ impl Flags {
     fn unset(&mut self, idx: usize) {
         let i = idx/64;
         let j = idx%64;
-        self.0[i] &= 0xFFFFFFFFFFFFFFFFu64 - (0x1u64 << j);
+        if (self.0[i] & (0x1u64 << j)) > 0 {
+            self.0[i] &= 0xFFFFFFFFFFFFFFFFu64 - (0x1u64 << j);
+            self.dec_rank(i,1);
+        }
     }
     /*#[inline]
     fn set(&mut self, idx: usize) {